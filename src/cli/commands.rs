@@ -1,7 +1,11 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, process};
 
-use clap::{Parser, Subcommand};
-use crate::qb::{self, data_dir, error::QboxError};
+use clap::{Parser, Subcommand, ValueEnum};
+use crate::qb::{self, version_store::Storage, data_dir, diff::FileDiff, error::QboxError, plan::Plan};
+
+const DRY_RUN_HELP: &str = "Print the plan without changing anything";
+const UNIFIED_HELP: &str = "Show unified diffs of changed text files";
+const ALL_HELP: &str = "Also list unchanged files";
 
 #[derive(Parser)]
 #[command(name = "myapp")]
@@ -20,17 +24,58 @@ enum Commands {
     }
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand)]
 enum QbCommands {
-    Make {name: String},    
+    /// List all qboxes
+    List {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    Make {
+        name: String,
+
+        /// Make the qbox a git repository, every record becomes a commit on the branch qbox/<version>
+        #[arg(long)]
+        git: bool,
+    },
     Delete {
         name: String,
         
         #[arg(long)]
         force: bool,
     },
+    /// Pack the whole qbox, with all versions, backups and state, into a tar.zst archive
+    Export {
+        name: String,
+
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Recreate a qbox or a version from an archive made by `export`
+    Import {
+        archive: PathBuf,
+
+        /// Import into this qbox instead of the one the archive was exported from
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Replace an existing qbox of the same name with the imported qbox
+        #[arg(long)]
+        force: bool,
+    },
     Open {
         name: String,
+
+        /// Merge the overlay of this profile onto the config
+        #[arg(long)]
+        profile: Option<String>,
+
         #[command(subcommand)]
         actions: QbActions
     }
@@ -39,37 +84,178 @@ enum QbCommands {
 #[derive(Subcommand)]
 enum QbActions {
     NewVer { name: String },
+    /// List versions of the qbox
+    Versions {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
     DelVer {
         name: String,
     
         #[arg(long)]
-        force: bool
+        force: bool,
+
+        #[arg(long, help = DRY_RUN_HELP)]
+        dry_run: bool,
     },
     Record {
         name: String,
         
         #[arg(long)]
-        force: bool
+        force: bool,
+
+        #[arg(long, help = DRY_RUN_HELP)]
+        dry_run: bool,
+    },
+    Backup {
+        #[arg(long, help = DRY_RUN_HELP)]
+        dry_run: bool,
     },
-    Backup,
     Apply {
         name: String,
         #[arg(long)]
-        force: bool
+        force: bool,
+
+        /// Do not save the changed files to roll back a failed apply
+        #[arg(long)]
+        no_backup: bool,
+
+        /// Link the target paths to a working copy in the version instead of copying the files
+        #[arg(long)]
+        link: bool,
+
+        #[arg(long, help = DRY_RUN_HELP)]
+        dry_run: bool,
+    },
+    /// Replace the links made by `apply --link` with copies and keep the edits in the version
+    Unlink {
+        /// Do not save the changed files to roll back a failed unlink
+        #[arg(long)]
+        no_backup: bool,
+
+        #[arg(long, help = DRY_RUN_HELP)]
+        dry_run: bool,
+    },
+    /// Inspect the config of the qbox
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCommands,
+    },
+    /// Manage the backup history
+    Backups {
+        #[command(subcommand)]
+        cmd: BackupCommands,
+    },
+    Verify { name: String },
+    /// Pack a recorded version with the config into a tar.zst archive
+    Export {
+        name: String,
+
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Show how the live files drifted from the last applied version
+    Status,
+    /// Compare a version with the live files
+    Diff {
+        name: String,
+
+        #[arg(long, help = UNIFIED_HELP)]
+        unified: bool,
+
+        #[arg(long, help = ALL_HELP)]
+        all: bool,
+    },
+    /// Compare two versions
+    DiffVersions {
+        old: String,
+        new: String,
+
+        #[arg(long, help = UNIFIED_HELP)]
+        unified: bool,
+
+        #[arg(long, help = ALL_HELP)]
+        all: bool,
     },
 }
 
-fn command_result<T, E: std::fmt::Display>(res: Result<T, E>, success: &str, failure: &str) {
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Report all problems of the config
+    Check,
+}
+
+#[derive(Subcommand)]
+enum BackupCommands {
+    List,
+    Restore {
+        id: String,
+
+        /// Do not save the changed files to roll back a failed restore
+        #[arg(long)]
+        no_backup: bool,
+
+        #[arg(long, help = DRY_RUN_HELP)]
+        dry_run: bool,
+    },
+}
+
+/// Exit code of `config check` when the config has errors, the same as for config errors.
+const CONFIG_ERRORS_EXIT_CODE: i32 = 2;
+/// Exit code of `verify` when the version is damaged.
+const DAMAGED_EXIT_CODE: i32 = 8;
+
+fn fail(failure: &str, e: &QboxError) -> ! {
+    eprintln!("{}: {}", failure, e);
+    process::exit(e.exit_code())
+}
+
+fn command_result<T>(res: Result<T, QboxError>, success: &str, failure: &str) {
     match res {
         Ok(_) => println!("{}", success),
-        Err(e) => eprintln!("{}: {}", failure, e),
+        Err(e) => fail(failure, &e),
+    }
+}
+
+fn print_json<T: serde::Serialize>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => {
+            eprintln!("Failed to format json: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_plan(res: Result<Plan, QboxError>) {
+    match res {
+        Ok(plan) => println!("{}", plan),
+        Err(e) => fail("Failed to plan", &e),
+    }
+}
+
+fn print_diff(res: Result<Vec<FileDiff>, QboxError>, all: bool) {
+    match res {
+        Ok(diffs) => {
+            let mut changed = 0;
+            for file_diff in diffs {
+                if file_diff.is_changed() {
+                    changed += 1;
+                } else if !all {
+                    continue;
+                }
+                println!("{}", file_diff);
+            }
+            println!("{} file(s) differ", changed);
+        }
+        Err(e) => fail("Failed to compare", &e),
     }
 }
 
-fn open_qbox(name: &str, data_dir: PathBuf) -> Result<qb::qbox::Qbox, QboxError>{
+fn open_qbox(name: &str, data_dir: PathBuf, profile: Option<&str>) -> Result<qb::qbox::Qbox, QboxError>{
     match qb::qbox::Qbox::new(name, data_dir) {
         Ok(mut qbox) => {
-            match qbox.open() {
+            match qbox.open_profile(profile) {
                 Ok(_) => {
                     Ok(qbox)
                 },
@@ -93,39 +279,172 @@ pub fn init() {
         }
         Commands::Qb { cmd } => {
             match cmd {
-                QbCommands::Make { name } => {
-                    command_result(qb::qbox::make(name.as_str(), data_dir()), &format!("Created {}", name), "Failed to create");
+                QbCommands::List { format } => {
+                    match qb::qbox::list(data_dir()) {
+                        Ok(names) => match format {
+                            OutputFormat::Text => names.iter().for_each(|name| println!("{}", name)),
+                            OutputFormat::Json => print_json(&names),
+                        },
+                        Err(e) => fail("Failed to list qboxes", &e),
+                    }
+                }
+                QbCommands::Make { name, git } => {
+                    let storage = if git { Storage::Git } else { Storage::Directory };
+                    command_result(qb::qbox::make_with(name.as_str(), data_dir(), storage), &format!("Created {}", name), "Failed to create");
                 }
                 QbCommands::Delete { name, force} => {
                     command_result(qb::qbox::delete(name.as_str(), data_dir(), force), &format!("Deleted {}", name), "Failed to delete");
                 }
-                QbCommands::Open { name, actions } => {
-                    let open_qbox: qb::qbox::Qbox = match open_qbox(name.as_str(), data_dir()) {
-                        Ok(qbox) => {qbox},
-                        Err(e) => {
-                            eprintln!("Failed to open qbox: {}", e);
-                            return;
+                QbCommands::Export { name, output } => {
+                    let res = qb::qbox::Qbox::new(name.as_str(), data_dir()).and_then(|qbox| qbox.export_box(&output));
+                    command_result(res, &format!("Exported {} to {}", name, output.display()), "Failed to export");
+                }
+                QbCommands::Import { archive, name, force } => {
+                    match qb::archive::import(&archive, data_dir(), name.as_deref(), force) {
+                        Ok(imported) => println!("Imported {}", imported),
+                        Err(e) => fail("Failed to import", &e),
+                    }
+                }
+                QbCommands::Open { name, profile, actions: QbActions::Config { cmd: ConfigCommands::Check } } => {
+                    // The config is checked without opening the qbox, opening fails on the first problem.
+                    let problems = qb::qbox::Qbox::new(name.as_str(), data_dir())
+                        .and_then(|qbox| qbox.check_config(profile.as_deref()));
+                    match problems {
+                        Ok(problems) => {
+                            let errors = problems.iter().filter(|p| p.severity == qb::check::Severity::Error).count();
+                            for problem in &problems {
+                                println!("{}", problem);
+                            }
+                            println!("{} error(s), {} warning(s)", errors, problems.len() - errors);
+                            if errors > 0 {
+                                process::exit(CONFIG_ERRORS_EXIT_CODE);
+                            }
                         }
+                        Err(e) => fail("Failed to check config", &e),
+                    }
+                }
+                QbCommands::Open { name, profile, actions } => {
+                    let open_qbox: qb::qbox::Qbox = match open_qbox(name.as_str(), data_dir(), profile.as_deref()) {
+                        Ok(qbox) => {qbox},
+                        Err(e) => fail("Failed to open qbox", &e),
                     };
                     
                     match actions {
                         QbActions::NewVer { name: ver } => {
-                            qb::qbox::check_keywords(ver.as_str()).unwrap_or_else(|e| {
-                                eprintln!("Keywords error: {}", e);
-                            });
+                            if let Err(e) = qb::qbox::check_keywords(ver.as_str()) {
+                                fail("Keywords error", &e);
+                            }
                             command_result(open_qbox.new_version(ver.as_str()), &format!("New version {} created in {}", ver, name), "Failed to create version");
                         }
-                        QbActions::DelVer { name: ver, force } => {
-                            command_result(open_qbox.remove_version(ver.as_str(), force), &format!("Deleted version {} from {} (force={})", ver, name, force), "Failed to delete version");
+                        QbActions::Versions { format } => {
+                            match open_qbox.version_infos() {
+                                Ok(infos) => match format {
+                                    OutputFormat::Text => {
+                                        for info in infos {
+                                            let recorded = info.recorded_at.map(qb::time::format_utc).unwrap_or_else(|| "not recorded".to_string());
+                                            println!("{}  {}  {} file(s), {} bytes", info.name, recorded, info.files, info.size);
+                                        }
+                                    }
+                                    OutputFormat::Json => print_json(&infos),
+                                },
+                                Err(e) => fail("Failed to list versions", &e),
+                            }
+                        }
+                        QbActions::DelVer { name: ver, force, dry_run } => {
+                            if dry_run {
+                                print_plan(open_qbox.plan_remove_version(ver.as_str(), force));
+                            } else {
+                                command_result(open_qbox.remove_version(ver.as_str(), force), &format!("Deleted version {} from {} (force={})", ver, name, force), "Failed to delete version");
+                            }
+                        }
+                        QbActions::Record { name: ver, force, dry_run } => {
+                            if dry_run {
+                                print_plan(open_qbox.plan_record(ver.as_str(), force));
+                            } else {
+                                command_result(open_qbox.record(ver.as_str(), force), &format!("Recorded version {} in {} (force={})", ver, name, force), "Failed to record version");
+                            }
+                        }
+                        QbActions::Backup { dry_run } => {
+                            if dry_run {
+                                print_plan(open_qbox.plan_backup());
+                            } else {
+                                command_result(open_qbox.make_backup(), &format!("Backup created for {}", name), "Failed to create backup");
+                            }
+                        }
+                        QbActions::Apply { name: ver, force, no_backup, link: true, dry_run } => {
+                            if dry_run {
+                                print_plan(open_qbox.plan_link(ver.as_str(), force));
+                            } else {
+                                command_result(open_qbox.link(ver.as_str(), force, no_backup), &format!("Linked version {} to {}", ver, name), "Failed to link version");
+                            }
+                        }
+                        QbActions::Apply { name: ver , force, no_backup, link: false, dry_run } => {
+                            if dry_run {
+                                print_plan(open_qbox.plan_apply(ver.as_str(), force));
+                            } else {
+                                command_result(open_qbox.apply(ver.as_str(), force, no_backup), &format!("Applied version {} to {}", ver, name), "Failed to apply version");
+                            }
+                        }
+                        QbActions::Config { .. } => unreachable!("config commands are handled before the qbox is opened"),
+                        QbActions::Unlink { no_backup, dry_run } => {
+                            if dry_run {
+                                print_plan(open_qbox.plan_unlink());
+                            } else {
+                                command_result(open_qbox.unlink(no_backup), &format!("Unlinked {}", name), "Failed to unlink");
+                            }
+                        }
+                        QbActions::Backups { cmd: BackupCommands::List } => {
+                            match open_qbox.backups() {
+                                Ok(backups) => {
+                                    for backup in backups {
+                                        println!("{}  {}  {} file(s), {} bytes", backup.id, qb::time::format_utc(backup.recorded_at), backup.files, backup.size);
+                                    }
+                                }
+                                Err(e) => fail("Failed to list backups", &e),
+                            }
+                        }
+                        QbActions::Backups { cmd: BackupCommands::Restore { id, no_backup, dry_run } } => {
+                            if dry_run {
+                                print_plan(open_qbox.plan_restore_backup(id.as_str()));
+                            } else {
+                                command_result(open_qbox.restore_backup(id.as_str(), no_backup), &format!("Restored backup {} of {}", id, name), "Failed to restore backup");
+                            }
+                        }
+                        QbActions::Status => {
+                            match open_qbox.status() {
+                                Ok(status) => {
+                                    println!("Applied version {} at {}", status.version, qb::time::format_utc(status.applied_at));
+                                    for (path, drift) in &status.drifted {
+                                        println!("{:>9} {}", drift, path.display());
+                                    }
+                                    if status.drifted.is_empty() {
+                                        println!("Live files match the version");
+                                    }
+                                }
+                                Err(e) => fail("Failed to get status", &e),
+                            }
+                        }
+                        QbActions::Verify { name: ver } => {
+                            match open_qbox.verify(ver.as_str()) {
+                                Ok(problems) if problems.is_empty() => println!("Version {} of {} is intact", ver, name),
+                                Ok(problems) => {
+                                    for problem in problems {
+                                        eprintln!("{}", problem);
+                                    }
+                                    eprintln!("Version {} of {} is damaged", ver, name);
+                                    process::exit(DAMAGED_EXIT_CODE);
+                                }
+                                Err(e) => fail("Failed to verify version", &e),
+                            }
                         }
-                        QbActions::Record { name: ver, force } => {
-                            command_result(open_qbox.record(ver.as_str(), force), &format!("Recorded version {} in {} (force={})", ver, name, force), "Failed to record version");
+                        QbActions::Export { name: ver, output } => {
+                            command_result(open_qbox.export(ver.as_str(), &output), &format!("Exported version {} of {} to {}", ver, name, output.display()), "Failed to export version");
                         }
-                        QbActions::Backup => {
-                            command_result(open_qbox.make_backup(), &format!("Backup created for {}", name), "Failed to create backup");
+                        QbActions::Diff { name: ver, unified, all } => {
+                            print_diff(open_qbox.diff(ver.as_str(), unified), all);
                         }
-                        QbActions::Apply { name: ver , force} => {
-                            command_result(open_qbox.apply(ver.as_str(), force), &format!("Applied version {} to {}", ver, name), "Failed to apply version");
+                        QbActions::DiffVersions { old, new, unified, all } => {
+                            print_diff(open_qbox.diff_versions(old.as_str(), new.as_str(), unified), all);
                         }
                     }
                 }
@@ -1,4 +1,6 @@
 use std::{fs, io};
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
 pub fn make(path: &str) -> io::Result<bool> {
@@ -23,27 +25,61 @@ pub fn delete(path: &str, force: bool) -> io::Result<bool> {
     }
 }
 
+/// Symlinked directories are followed, see `read_all_links`.
 pub fn read_all(path: &Path, exclude: Option<&Vec<&str>>) -> io::Result<Vec<PathBuf>> {
+    read_all_links(path, exclude, true)
+}
+
+/// With `follow_links` symlinked directories are read as well, each directory only once,
+/// so cyclic links do not recurse forever. Without it symlinks are returned as files.
+pub fn read_all_links(path: &Path, exclude: Option<&Vec<&str>>, follow_links: bool) -> io::Result<Vec<PathBuf>> {
     let binding = Vec::new();
     let exclude = exclude.unwrap_or(&binding);
+    read_all_filtered(path, follow_links, &|entry, _| exclude.iter().any(|e| entry.ends_with(e)))
+}
+
+/// Reads all files the same way as `read_all_links`.
+/// `skip` gets every entry and whether it is a directory, skipped directories are not read.
+pub fn read_all_filtered(path: &Path, follow_links: bool, skip: &dyn Fn(&Path, bool) -> bool) -> io::Result<Vec<PathBuf>> {
     let mut curr: Vec<PathBuf> = Vec::new();
-    if path.is_dir() {
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            if !exclude.iter().any(|e| entry.path().ends_with(e)) {
-                if entry.path().is_dir() {
-                    let sub = read_all(&entry.path(), Some(exclude))?;
-                    curr.extend(sub);
-                } else {
-                    curr.push(entry.path());
-                }
-            }
-        }
-        return Ok(curr);
-    } else {
+    let mut visited: HashSet<(u64, u64)> = HashSet::new();
+    read_dir_into(path, follow_links, skip, &mut visited, &mut curr)?;
+    Ok(curr)
+}
+
+fn read_dir_into(path: &Path, follow_links: bool, skip: &dyn Fn(&Path, bool) -> bool, visited: &mut HashSet<(u64, u64)>, curr: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !is_dir(path, follow_links) {
         curr.push(path.to_path_buf());
+        return Ok(());
     }
-    Ok(curr)
+    let metadata = fs::metadata(path)?;
+    if !visited.insert((metadata.dev(), metadata.ino())) {
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if !skip(&entry_path, is_dir(&entry_path, follow_links)) {
+            read_dir_into(&entry_path, follow_links, skip, visited, curr)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_dir(path: &Path, follow_links: bool) -> bool {
+    if !follow_links && is_symlink(path) {
+        return false;
+    }
+    path.is_dir()
+}
+
+/// Whether the path itself is a symlink, the link target does not have to exist.
+pub fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+/// Whether anything exists at the path, a dangling symlink included.
+pub fn entry_exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
 }
 
 pub fn path_exists(path: &Path) -> io::Result<()>{
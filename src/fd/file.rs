@@ -1,5 +1,8 @@
 use std::{io, fs};
+use std::fs::{File, FileTimes};
+use std::os::unix::fs::{chown, PermissionsExt};
 use std::path::Path;
+use std::time::SystemTime;
 
 use crate::fd::dir;
 
@@ -17,4 +20,21 @@ pub fn create_in_dir(filename: &Path, target_dir: &Path) -> io::Result<()>{
     fs::create_dir_all(new_file_dir_path)?;
     fs::copy(str_filename, &new_file_path)?;
     Ok(())
-}
\ No newline at end of file
+}
+
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+/// Changing the owner requires privileges; without them the file is left as is and `false` is returned.
+pub fn set_owner(path: &Path, uid: u32, gid: u32) -> io::Result<bool> {
+    match chown(path, Some(uid), Some(gid)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn set_times(path: &Path, accessed: SystemTime, modified: SystemTime) -> io::Result<()> {
+    File::open(path)?.set_times(FileTimes::new().set_accessed(accessed).set_modified(modified))
+}
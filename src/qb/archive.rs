@@ -0,0 +1,364 @@
+use std::{collections::{HashMap, HashSet}, fmt, fs::{self, File}, io::{Read, Write}, os::unix::fs::{symlink, PermissionsExt}, path::{Component, Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+use crate::{fd, qb::{config::{read_config, Config}, error::QboxError, git::GIT_DIR, manifest::{Manifest, ManifestEntry, Mapping}, qbox::{self, Qbox}, store, template, time, version_store, QBOX_CONFIG_NAME}};
+
+/// Name of the archive entry that describes the exported version.
+pub const ARCHIVE_INFO_NAME: &str = "version.yaml";
+/// Directory of the archive with the content of the files, one subdirectory per mapping.
+pub const ARCHIVE_FILES_DIR: &str = "files";
+pub const BOX_INFO_NAME: &str = "box.yaml";
+pub const BOX_FILES_DIR: &str = "box";
+
+type ArchiveWriter = tar::Builder<zstd::Encoder<'static, tempfile::NamedTempFile>>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveInfo {
+    pub qbox: String,
+    pub version: String,
+    pub exported_at: u64,
+    /// Mappings of the exported files, as written in qbox.yaml.
+    pub mappings: Vec<Mapping>,
+    /// Manifest of the version. The path of every entry is the path of its content in the archive,
+    /// `files/<mapping index>/<path relative to the mapping>`, so the archive does not depend
+    /// on where the mapping was on the exporting machine.
+    pub manifest: Manifest,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BoxArchiveInfo {
+    pub qbox: String,
+    pub exported_at: u64,
+    /// Versions of the qbox, recreated even when they hold no files.
+    pub versions: Vec<String>,
+    /// Every file of the qbox directory, stored in the archive under `box/<path>`.
+    pub files: Vec<BoxFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BoxFile {
+    /// Path relative to the qbox directory.
+    pub path: PathBuf,
+    pub hash: String,
+    pub size: u64,
+    pub mode: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Imported {
+    Version { qbox: String, version: String },
+    Qbox(String),
+}
+
+impl fmt::Display for Imported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Imported::Version { qbox, version } => write!(f, "version {} into {}", version, qbox),
+            Imported::Qbox(qbox) => write!(f, "qbox {}", qbox),
+        }
+    }
+}
+
+impl Qbox {
+    /// Packs the recorded version into a zstd compressed tar archive at `output`.
+    /// The archive holds the config of the qbox, the description of the version
+    /// and the content of its files. Symlinks are kept in the description only.
+    pub fn export(&self, version: &str, output: &Path) -> Result<(), QboxError> {
+        self.check_not_linked(version)?;
+        let mut manifest = self.recorded_manifest(version)?;
+        let mut mappings: Vec<Mapping> = Vec::new();
+        let mut contents = Vec::new();
+        for entry in &mut manifest.files {
+            let index = match mappings.iter().position(|mapping| *mapping == entry.mapping) {
+                Some(index) => index,
+                None => {
+                    mappings.push(entry.mapping.clone());
+                    mappings.len() - 1
+                }
+            };
+            entry.path = Path::new(ARCHIVE_FILES_DIR).join(index.to_string()).join(&entry.relative);
+            if entry.link.is_none() {
+                contents.push((entry.path.clone(), self.store.object_path(&entry.hash)));
+            }
+        }
+        let info = ArchiveInfo { qbox: self.name(), version: version.to_string(), exported_at: time::now(), mappings, manifest };
+        let config = self.export_config()?;
+        write_archive(output, |builder| {
+            append_bytes(builder, QBOX_CONFIG_NAME, &config)?;
+            append_bytes(builder, ARCHIVE_INFO_NAME, serde_yaml::to_string(&info)?.as_bytes())?;
+            for (path, object_path) in contents {
+                builder.append_path_with_name(object_path, path)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Packs the whole qbox into a zstd compressed tar archive at `output`:
+    /// the config, the state, every version with its objects and the backups.
+    /// Every file is listed with its hash, so `import` can check that nothing was damaged.
+    pub fn export_box(&self, output: &Path) -> Result<(), QboxError> {
+        // Hidden entries are unfinished transactions, they are not part of the qbox.
+        // The git repository of a git-backed qbox is the only exception.
+        let paths = fd::dir::read_all_filtered(&self.qbox_path, false, &|entry, _| {
+            entry.parent() == Some(self.qbox_path.as_path())
+                && entry.file_name().is_some_and(|name| name != GIT_DIR && name.to_string_lossy().starts_with('.'))
+        })?;
+        let mut files = Vec::new();
+        for path in paths {
+            let relative = path.strip_prefix(&self.qbox_path).expect("file is inside the qbox").to_path_buf();
+            let metadata = fs::symlink_metadata(&path)?;
+            let file = if metadata.file_type().is_symlink() {
+                let link = fs::read_link(&path)?;
+                BoxFile { path: relative, hash: store::hash_link(&link), size: 0, mode: metadata.permissions().mode(), link: Some(link) }
+            } else {
+                BoxFile { path: relative, hash: store::hash_file(&path)?, size: metadata.len(), mode: metadata.permissions().mode(), link: None }
+            };
+            files.push(file);
+        }
+        let info = BoxArchiveInfo { qbox: self.name(), exported_at: time::now(), versions: self.versions()?, files };
+        write_archive(output, |builder| {
+            append_bytes(builder, BOX_INFO_NAME, serde_yaml::to_string(&info)?.as_bytes())?;
+            for file in &info.files {
+                let archive_path = Path::new(BOX_FILES_DIR).join(&file.path);
+                match &file.link {
+                    Some(link) => {
+                        let mut header = tar::Header::new_gnu();
+                        header.set_entry_type(tar::EntryType::Symlink);
+                        header.set_size(0);
+                        builder.append_link(&mut header, archive_path, link)?;
+                    }
+                    None => builder.append_path_with_name(self.qbox_path.join(&file.path), archive_path)?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Included files are not exported, a config with includes is exported merged.
+    fn export_config(&self) -> Result<Vec<u8>, QboxError> {
+        let config_path = self.qbox_path.join(QBOX_CONFIG_NAME);
+        let config = read_config(config_path.clone())?;
+        if config.include.is_empty() {
+            return Ok(fs::read(config_path)?);
+        }
+        Ok(serde_yaml::to_string(&Config { include: Vec::new(), ..config })?.into_bytes())
+    }
+}
+
+/// Imports an archive made by `Qbox::export` or `Qbox::export_box`, into the qbox named
+/// in the archive or into `name`. An existing qbox is replaced by an exported qbox only with `force`.
+pub fn import(archive_path: &Path, data_dir: PathBuf, name: Option<&str>, force: bool) -> Result<Imported, QboxError> {
+    let invalid = |reason: String| QboxError::InvalidArchive { path: archive_path.to_path_buf(), reason };
+    let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(archive_path)?)?);
+    let mut entries = archive.entries()?;
+    let mut first = entries.next().ok_or_else(|| invalid("archive is empty".to_string()))??;
+    let first_path = first.path()?.to_path_buf();
+    let mut content = Vec::new();
+    first.read_to_end(&mut content)?;
+    if first_path == Path::new(BOX_INFO_NAME) {
+        let info: BoxArchiveInfo = serde_yaml::from_slice(&content)?;
+        let qbox_name = name.unwrap_or(&info.qbox).to_string();
+        qbox::check_name(&qbox_name)?;
+        import_box(entries, &info, &qbox_name, data_dir, force, &invalid)?;
+        return Ok(Imported::Qbox(qbox_name));
+    }
+    if first_path != Path::new(QBOX_CONFIG_NAME) {
+        return Err(invalid(format!("unexpected entry {}", first_path.display())));
+    }
+    let mut next = entries.next().ok_or_else(|| invalid(format!("{} is missing", ARCHIVE_INFO_NAME)))??;
+    if next.path()? != Path::new(ARCHIVE_INFO_NAME) {
+        return Err(invalid(format!("expected {}, found {}", ARCHIVE_INFO_NAME, next.path()?.display())));
+    }
+    let mut info_content = Vec::new();
+    next.read_to_end(&mut info_content)?;
+    let info: ArchiveInfo = serde_yaml::from_slice(&info_content)?;
+    let qbox_name = name.unwrap_or(&info.qbox).to_string();
+    qbox::check_name(&qbox_name)?;
+    import_version(entries, &info, content, &qbox_name, data_dir, &invalid)?;
+    Ok(Imported::Version { qbox: qbox_name, version: info.version })
+}
+
+/// Recreates an exported version. A missing qbox is created with the config of the archive,
+/// an existing qbox keeps its own config. The paths of the files are resolved with the config
+/// of the qbox, so variables like `$HOME` get the values of this machine.
+fn import_version<R: Read>(
+    entries: tar::Entries<R>,
+    info: &ArchiveInfo,
+    config: Vec<u8>,
+    qbox_name: &str,
+    data_dir: PathBuf,
+    invalid: &dyn Fn(String) -> QboxError,
+) -> Result<(), QboxError> {
+    qbox::check_keywords(&info.version)?;
+    for entry in &info.manifest.files {
+        // The files are applied at `relative` below the mapping target, so it must stay inside it.
+        if !is_inside(&entry.relative) || !is_inside(&entry.path) {
+            return Err(invalid(format!("path {} is outside of its mapping", entry.relative.display())));
+        }
+    }
+    let qbox_path = qbox::make_qbox_path(qbox_name, data_dir.clone())?;
+    let created = !qbox_path.exists();
+    if created {
+        qbox::make(qbox_name, data_dir.clone())?;
+        fs::write(qbox_path.join(QBOX_CONFIG_NAME), config)?;
+    }
+
+    let mut pending: HashMap<PathBuf, &str> = info.manifest.files.iter()
+        .filter(|entry| entry.link.is_none())
+        .map(|entry| (entry.path.clone(), entry.hash.as_str()))
+        .collect();
+    let qbox = Qbox::new(qbox_name, data_dir)?;
+    // Objects that were there before the import, the others are removed if it fails.
+    let existing: HashSet<String> = qbox.store.list()?.into_iter().collect();
+    let import_files = || -> Result<(), QboxError> {
+        if qbox.version_store.metadata(&info.version)?.is_some() {
+            return Err(
+                QboxError::VersionExists(qbox.version_store.location(&info.version))
+            );
+        }
+        for entry in entries {
+            let mut entry = entry?;
+            let path = entry.path()?.to_path_buf();
+            let Some(hash) = pending.remove(&path) else {
+                return Err(invalid(format!("unexpected entry {}", path.display())));
+            };
+            if qbox.store.put_reader(&mut entry)? != hash {
+                return Err(invalid(format!("content of {} does not match its hash", path.display())));
+            }
+        }
+        if let Some(path) = pending.keys().next() {
+            return Err(invalid(format!("content of {} is missing", path.display())));
+        }
+        write_manifest(&qbox, &info.version, &info.manifest, invalid)
+    };
+    if let Err(e) = import_files() {
+        if created {
+            fs::remove_dir_all(&qbox_path)?;
+        } else {
+            qbox.store.gc(&existing)?;
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Writes the manifest of the imported version with the paths of this machine.
+/// Every file must belong to a mapping of the config, the archive does not decide where files go.
+fn write_manifest(qbox: &Qbox, version: &str, manifest: &Manifest, invalid: &dyn Fn(String) -> QboxError) -> Result<(), QboxError> {
+    let mut config = read_config(qbox.qbox_path.join(QBOX_CONFIG_NAME))?;
+    config.apply_overlays(&template::hostname(), None)?;
+    let mappings: Vec<Mapping> = config.mappings().iter().map(|mapping| mapping.paths()).collect();
+    let mut files = Vec::new();
+    for entry in &manifest.files {
+        if !mappings.contains(&entry.mapping) {
+            return Err(invalid(format!("mapping {} of {} is not in the config", entry.mapping.source.display(), entry.relative.display())));
+        }
+        let (source, _) = config.resolve_mapping(&entry.mapping)?;
+        files.push(ManifestEntry { path: source.join(&entry.relative), ..entry.clone() });
+    }
+    qbox.version_store.create(version)?;
+    Manifest { recorded_at: manifest.recorded_at, config: manifest.config.clone(), files }.write_to(qbox.version_store.as_ref(), version)
+}
+
+/// Recreates an exported qbox. The files are unpacked next to the boxes and checked against
+/// their hashes, the qbox only takes its place once everything is in order.
+/// With `force` an existing qbox of the same name is replaced, otherwise it is a conflict.
+fn import_box<R: Read>(
+    entries: tar::Entries<R>,
+    info: &BoxArchiveInfo,
+    qbox_name: &str,
+    data_dir: PathBuf,
+    force: bool,
+    invalid: &dyn Fn(String) -> QboxError,
+) -> Result<(), QboxError> {
+    let qbox_path = qbox::make_qbox_path(qbox_name, data_dir.clone())?;
+    if qbox_path.exists() && !force {
+        return Err(
+            QboxError::BoxExists(qbox_path)
+        );
+    }
+    let boxes_path = qbox::get_boxes_path(data_dir);
+    let staging = tempfile::Builder::new().prefix(".import").tempdir_in(&boxes_path)?;
+    let mut pending: HashMap<PathBuf, &BoxFile> = HashMap::new();
+    for file in &info.files {
+        // Paths leaving the qbox directory would be written outside of it.
+        if !is_inside(&file.path) {
+            return Err(invalid(format!("path {} is outside of the qbox", file.path.display())));
+        }
+        pending.insert(Path::new(BOX_FILES_DIR).join(&file.path), file);
+    }
+    for entry in entries {
+        let mut entry = entry?;
+        let archive_path = entry.path()?.to_path_buf();
+        let Some(file) = pending.remove(&archive_path) else {
+            return Err(invalid(format!("unexpected entry {}", archive_path.display())));
+        };
+        let path = staging.path().join(&file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|source| QboxError::CreateDir { path: parent.to_path_buf(), source })?;
+        }
+        let hash = match &file.link {
+            Some(link) => {
+                symlink(link, &path)?;
+                store::hash_link(link)
+            }
+            None => {
+                let hash = store::copy_hashed(&mut entry, &mut File::create(&path)?)?;
+                fs::set_permissions(&path, fs::Permissions::from_mode(file.mode))?;
+                hash
+            }
+        };
+        if hash != file.hash {
+            return Err(invalid(format!("content of {} does not match its hash", file.path.display())));
+        }
+    }
+    if let Some(path) = pending.keys().next() {
+        return Err(invalid(format!("content of {} is missing", path.display())));
+    }
+    // Versions without files, like a fresh one, have nothing in the archive.
+    let versions = version_store::open(staging.path());
+    for version in &info.versions {
+        if versions.metadata(version)?.is_none() {
+            versions.create(version)?;
+        }
+    }
+    // The replaced qbox is moved aside first and only deleted once the imported one is in place.
+    let replaced = tempfile::Builder::new().prefix(".replaced").tempdir_in(&boxes_path)?;
+    let moved = replaced.path().join(qbox_name);
+    if qbox_path.exists() {
+        fs::rename(&qbox_path, &moved)?;
+    }
+    if let Err(e) = fs::rename(staging.path(), &qbox_path) {
+        if moved.exists() {
+            fs::rename(&moved, &qbox_path)?;
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Whether the relative path stays below the directory it is joined to.
+fn is_inside(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Writes the archive next to the output first, a failed export leaves no partial archive.
+fn write_archive(output: &Path, fill: impl FnOnce(&mut ArchiveWriter) -> Result<(), QboxError>) -> Result<(), QboxError> {
+    let output_dir = output.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let tmp = tempfile::NamedTempFile::new_in(output_dir)?;
+    let mut builder = tar::Builder::new(zstd::Encoder::new(tmp, zstd::DEFAULT_COMPRESSION_LEVEL)?);
+    fill(&mut builder)?;
+    let tmp = builder.into_inner()?.finish()?;
+    tmp.persist(output).map_err(|e| e.error)?;
+    Ok(())
+}
+
+fn append_bytes<W: Write>(builder: &mut tar::Builder<W>, path: &str, content: &[u8]) -> Result<(), QboxError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(time::now());
+    builder.append_data(&mut header, path, content)?;
+    Ok(())
+}
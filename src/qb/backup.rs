@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::qb::{error::QboxError, manifest::{Manifest, ManifestEntry, MANIFEST_NAME}, plan::{Operation, Plan}, qbox::{self, ManifestOwner, Qbox}, time};
+
+pub const BACKUPS_DIR: &str = "backups";
+
+/// Retention of backups, the `backup` section of qbox.yaml.
+/// A backup is deleted as soon as it breaks any of the rules,
+/// the newest backup is never deleted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct BackupPolicy {
+    /// Keep only this many newest backups.
+    pub keep_last: Option<usize>,
+    /// Keep backups for this many days.
+    pub keep_days: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackupInfo {
+    pub id: String,
+    /// Unix time the backup was made.
+    pub recorded_at: u64,
+    pub files: usize,
+    pub size: u64,
+}
+
+impl Qbox {
+    pub(crate) fn backup_path(&self, id: &str) -> PathBuf {
+        self.qbox_path.join(BACKUPS_DIR).join(id)
+    }
+
+    /// All backups of the qbox, from the oldest to the newest.
+    pub fn backups(&self) -> Result<Vec<BackupInfo>, QboxError> {
+        let backups_path = self.qbox_path.join(BACKUPS_DIR);
+        let mut backups = Vec::new();
+        if !backups_path.exists() {
+            return Ok(backups);
+        }
+        for entry in backups_path.read_dir()? {
+            let entry = entry?;
+            if !entry.path().join(MANIFEST_NAME).exists() {
+                continue;
+            }
+            let manifest = Manifest::read(&entry.path())?;
+            backups.push(BackupInfo {
+                id: entry.file_name().to_string_lossy().to_string(),
+                recorded_at: manifest.recorded_at,
+                files: manifest.files.len(),
+                size: manifest.files.iter().map(|f| f.size).sum(),
+            });
+        }
+        backups.sort_by(|a, b| (a.recorded_at, &a.id).cmp(&(b.recorded_at, &b.id)));
+        Ok(backups)
+    }
+
+    /// Records the current content of all target paths into a new backup
+    /// and deletes the backups that are out of the retention policy.
+    /// Files are restored to their absolute target paths.
+    pub fn make_backup(&self) -> Result<(), QboxError>{
+        self.plan_backup()?.execute(&self.store, self.version_store.as_ref())
+    }
+
+    pub fn plan_backup(&self) -> Result<Plan, QboxError>{
+        let now = time::now();
+        let backup_path = self.backup_path(&self.new_backup_id(now));
+        let mut plan = Plan::new();
+        plan.push(Operation::MakeDir(backup_path.clone()));
+        let mut files = Vec::new();
+        for mapping in self.raw_config.mappings() {
+            let (_, target_path) = self.config.resolve_mapping(&mapping.paths())?;
+            files.extend(self.collect_files(&mapping, target_path)?);
+        }
+        let mut manifest = Manifest::new();
+        manifest.extend(self.plan_store_files(&mut plan, &files, &Manifest::new())?);
+        manifest.recorded_at = now;
+        manifest.config = self.raw_config.clone();
+
+        let pruned: Vec<PathBuf> = self.pruned_backups(now)?.iter().map(|id| self.backup_path(id)).collect();
+        let mut replaced = vec![(ManifestOwner::Backup(backup_path.clone()), Some(&manifest))];
+        replaced.extend(pruned.iter().map(|path| (ManifestOwner::Backup(path.clone()), None)));
+        let unreferenced = self.unreferenced_objects(&replaced)?;
+
+        plan.push(Operation::WriteManifest { path: backup_path, manifest: Box::new(manifest) });
+        for path in pruned {
+            plan.push(Operation::RemoveDir { path, recursive: true });
+        }
+        for hash in unreferenced {
+            plan.push(Operation::DeleteObject(hash));
+        }
+        Ok(plan)
+    }
+
+    /// If a backup with the same id exists, a counter is appended.
+    fn new_backup_id(&self, now: u64) -> String {
+        let base = time::format_compact(now);
+        let mut id = base.clone();
+        let mut counter = 1;
+        while self.backup_path(&id).exists() {
+            id = format!("{}-{}", base, counter);
+            counter += 1;
+        }
+        id
+    }
+
+    /// Ids of the existing backups that break the retention policy once a new backup is made.
+    fn pruned_backups(&self, now: u64) -> Result<Vec<String>, QboxError> {
+        let policy = &self.config.backup;
+        let backups = self.backups()?;
+        let mut pruned = Vec::new();
+        // The new backup is the newest one, so it takes one place of `keep_last`.
+        let keep_existing = policy.keep_last.map(|n| n.saturating_sub(1));
+        for (i, backup) in backups.iter().enumerate() {
+            let too_many = keep_existing.is_some_and(|n| backups.len() - i > n);
+            let too_old = policy.keep_days.is_some_and(|days| backup.recorded_at + days * time::SECONDS_IN_DAY < now);
+            if too_many || too_old {
+                pruned.push(backup.id.clone());
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Restores all files of the backup to their paths.
+    /// A failed restore is rolled back unless `no_backup` is set.
+    pub fn restore_backup(&self, id: &str, no_backup: bool) -> Result<(), QboxError> {
+        let plan = self.plan_restore_backup(id)?;
+        self.execute(&plan, no_backup)
+    }
+
+    pub fn plan_restore_backup(&self, id: &str) -> Result<Plan, QboxError> {
+        qbox::check_name(id)?;
+        let backup_path = self.backup_path(id);
+        if !backup_path.join(MANIFEST_NAME).exists() {
+            return Err(
+                QboxError::BackupMissing(backup_path)
+            );
+        }
+        let manifest = Manifest::read(&backup_path)?;
+        let targets: Vec<(&ManifestEntry, PathBuf)> = manifest.files.iter()
+            .map(|entry| (entry, entry.path.clone()))
+            .collect();
+        self.plan_copy(&targets, false)
+    }
+
+    pub(crate) fn plan_restore_latest_backup(&self) -> Result<Plan, QboxError> {
+        match self.backups()?.last() {
+            Some(backup) => self.plan_restore_backup(&backup.id),
+            None => Err(
+                QboxError::BackupMissing(self.qbox_path.join(BACKUPS_DIR))
+            ),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::{fmt, fs, path::{Path, PathBuf}};
+use crate::qb::{config::{read_config, Config, FileMapping}, error::QboxError, ignore, qbox::Qbox, template, QBOX_CONFIG_NAME};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The qbox can not be opened with this config.
+    Error,
+    /// The config works, but probably not the way it was meant to.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found in the config by `check_config`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    pub file: PathBuf,
+    /// Line and column in the file, both starting at 1, if the place is known.
+    pub location: Option<(usize, usize)>,
+    /// The path or pattern of the config entry with the problem.
+    pub entry: Option<String>,
+    pub message: String,
+    pub fix: Option<String>,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.file.display())?;
+        if let Some((line, column)) = self.location {
+            write!(f, ":{}:{}", line, column)?;
+        }
+        if let Some(entry) = &self.entry {
+            write!(f, ": {}", entry)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n  fix: {}", fix)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the problems of a config file, locating the entries in its text.
+struct Report {
+    file: PathBuf,
+    text: String,
+    problems: Vec<ConfigProblem>,
+}
+
+impl Report {
+    fn push(&mut self, severity: Severity, entry: Option<&str>, message: &str, fix: Option<&str>) {
+        self.problems.push(ConfigProblem {
+            severity,
+            file: self.file.clone(),
+            location: entry.and_then(|entry| locate(&self.text, entry)),
+            entry: entry.map(str::to_string),
+            message: message.to_string(),
+            fix: fix.map(str::to_string),
+        });
+    }
+
+    fn push_expand_error(&mut self, entry: &str, error: &QboxError) {
+        let fix = match error {
+            QboxError::ConfigUndefinedVariable(_) => "define the variable in `vars:` or in the environment, or give it a default: ${VAR:-default}",
+            QboxError::InvalidVariableSyntax(_) => "close the variable with `}`: ${VAR}",
+            QboxError::VariableCycle(_) => "make the variables in `vars:` not refer to each other in a loop",
+            _ => "check the variables of the path",
+        };
+        self.push(Severity::Error, Some(entry), &error.to_string(), Some(fix));
+    }
+}
+
+impl Qbox {
+    /// Checks the config of the qbox without opening it and reports all problems at once.
+    /// Every mapping and exclude is checked, overlapping mappings and excludes
+    /// that match no file are reported as warnings.
+    pub fn check_config(&self, profile: Option<&str>) -> Result<Vec<ConfigProblem>, QboxError> {
+        let config_path = self.qbox_path.join(QBOX_CONFIG_NAME);
+        if !config_path.exists() {
+            return Err(QboxError::MissingConfig(config_path));
+        }
+        let mut report = Report { text: fs::read_to_string(&config_path)?, file: config_path.clone(), problems: Vec::new() };
+        let mut config = match read_config(config_path) {
+            Ok(config) => config,
+            Err(error) => {
+                push_read_error(&mut report, error);
+                return Ok(report.problems);
+            }
+        };
+        if let Err(error) = config.apply_overlays(&template::hostname(), profile) {
+            report.push(Severity::Error, profile, &error.to_string(), Some("define the profile in `profiles:` or select another one"));
+            return Ok(report.problems);
+        }
+        for name in config.vars.keys() {
+            if let Err(error) = config.format_path(Path::new(&format!("${{{}}}", name)), false) {
+                report.push_expand_error(name, &error);
+            }
+        }
+        let mut resolved: Vec<(FileMapping, PathBuf, PathBuf)> = Vec::new();
+        for mapping in config.mappings() {
+            if let Some(paths) = check_mapping(&mut report, &config, &mapping) {
+                resolved.push((mapping, paths.0, paths.1));
+            }
+        }
+        check_overlaps(&mut report, &resolved);
+        check_excludes(&mut report, &config, &resolved);
+        Ok(report.problems)
+    }
+}
+
+/// Turns an error of reading the config into a problem, with the YAML position of parse errors.
+fn push_read_error(report: &mut Report, error: QboxError) {
+    match error {
+        QboxError::ConfigParse(e) => {
+            report.problems.push(ConfigProblem {
+                severity: Severity::Error,
+                file: report.file.clone(),
+                location: e.location().map(|l| (l.line(), l.column())),
+                entry: None,
+                message: e.to_string(),
+                fix: Some("fix the YAML syntax or the field names".to_string()),
+            });
+        }
+        QboxError::IncludedConfig(path, e) => {
+            let location = match e.as_ref() {
+                QboxError::ConfigParse(e) => e.location().map(|l| (l.line(), l.column())),
+                _ => None,
+            };
+            report.problems.push(ConfigProblem {
+                severity: Severity::Error,
+                file: path,
+                location,
+                entry: None,
+                message: e.to_string(),
+                fix: Some("fix the included file or remove it from `include:`".to_string()),
+            });
+        }
+        QboxError::IncludeCycle(_) => {
+            report.push(Severity::Error, None, &error.to_string(), Some("remove one of the includes of the cycle"));
+        }
+        error => report.push(Severity::Error, None, &error.to_string(), None),
+    }
+}
+
+/// Checks the source and target of the mapping, returns them expanded if both are usable.
+fn check_mapping(report: &mut Report, config: &Config, mapping: &FileMapping) -> Option<(PathBuf, PathBuf)> {
+    let source_entry = mapping.source.to_string_lossy().to_string();
+    let source = check_path(report, config, &source_entry, "source")?;
+    if !source.exists() {
+        report.push(Severity::Error, Some(&source_entry), "source path does not exist", Some("create the path or remove the mapping"));
+    }
+    let target = if mapping.target == "*" {
+        source.clone()
+    } else {
+        let target = check_path(report, config, &mapping.target, "target")?;
+        if !config.make_dir && !target.exists() {
+            report.push(Severity::Error, Some(&mapping.target), "target path does not exist", Some("create the path or set `make_dir: true`"));
+        }
+        target
+    };
+    for pattern in &mapping.excludes {
+        check_pattern(report, config, pattern, "exclude", &[(mapping.clone(), source.clone(), target.clone())]);
+    }
+    for pattern in &mapping.include {
+        if let Err(error) = ::ignore::overrides::OverrideBuilder::new(&source).add(pattern) {
+            report.push(Severity::Error, Some(pattern), &format!("invalid include pattern: {}", error), Some("fix the glob syntax"));
+        }
+    }
+    Some((source, target))
+}
+
+fn check_path(report: &mut Report, config: &Config, entry: &str, kind: &str) -> Option<PathBuf> {
+    let path = match config.format_path(Path::new(entry), false) {
+        Ok(path) => path,
+        Err(error) => {
+            report.push_expand_error(entry, &error);
+            return None;
+        }
+    };
+    if !path.is_absolute() {
+        report.push(Severity::Error, Some(entry), &format!("{} path must be absolute", kind), Some("start the path with `/`, `~/` or a variable like `$HOME/`"));
+        return None;
+    }
+    if entry.ends_with('/') {
+        report.push(Severity::Error, Some(entry), &format!("{} path must not end with '/'", kind), Some("remove the trailing '/'"));
+    }
+    Some(path)
+}
+
+/// Warns about mappings whose sources or targets are inside each other.
+fn check_overlaps(report: &mut Report, resolved: &[(FileMapping, PathBuf, PathBuf)]) {
+    for (i, (mapping, source, target)) in resolved.iter().enumerate() {
+        for (other, other_source, other_target) in &resolved[i + 1..] {
+            let source_entry = mapping.source.to_string_lossy().to_string();
+            if source.starts_with(other_source) || other_source.starts_with(source) {
+                report.push(
+                    Severity::Warning, Some(&source_entry),
+                    &format!("source overlaps with the source of mapping {}, the files are recorded twice", other.source.display()),
+                    Some("exclude the inner directory from the outer mapping or remove one of the mappings"),
+                );
+            } else if target.starts_with(other_target) || other_target.starts_with(target) {
+                report.push(
+                    Severity::Warning, Some(&mapping.target),
+                    &format!("target overlaps with the target of mapping {}, applied files may overwrite each other", other.source.display()),
+                    Some("map the sources to separate target directories"),
+                );
+            }
+        }
+    }
+}
+
+/// Checks the excludes and the encrypt patterns of the qbox against every mapping.
+fn check_excludes(report: &mut Report, config: &Config, resolved: &[(FileMapping, PathBuf, PathBuf)]) {
+    for exclude in &config.excludes {
+        check_pattern(report, config, &exclude.to_string_lossy(), "exclude", resolved);
+    }
+    for pattern in &config.encrypt {
+        check_pattern(report, config, pattern, "encrypt pattern", resolved);
+    }
+}
+
+/// Checks that the pattern is valid and matches at least one source file,
+/// `kind` names the pattern in the warning.
+fn check_pattern(report: &mut Report, config: &Config, pattern: &str, kind: &str, resolved: &[(FileMapping, PathBuf, PathBuf)]) {
+    let expanded = match config.format_path(Path::new(pattern), false) {
+        Ok(expanded) => expanded.to_string_lossy().to_string(),
+        Err(error) => {
+            report.push_expand_error(pattern, &error);
+            return;
+        }
+    };
+    let mut matched = false;
+    for (_, source, _) in resolved {
+        match ignore::matches_any(&expanded, source) {
+            Ok(true) => matched = true,
+            Ok(false) => {}
+            Err(error) => {
+                report.push(Severity::Error, Some(pattern), &error.to_string(), Some("fix the pattern syntax, see gitignore patterns"));
+                return;
+            }
+        }
+    }
+    // A negated pattern takes files back, it does not have to exclude anything.
+    if !matched && !expanded.starts_with('!') {
+        report.push(Severity::Warning, Some(pattern), &format!("{} matches no file of any mapping", kind), Some("fix the pattern or remove it"));
+    }
+}
+
+const ENTRY_SECTIONS: [&str; 5] = ["files", "excludes", "vars", "profiles", "hosts"];
+
+/// Line and column of the entry in the file, both starting at 1.
+/// Only whole keys and values inside the sections with entries count,
+/// a comment or a longer path that merely contains the entry is skipped.
+fn locate(text: &str, entry: &str) -> Option<(usize, usize)> {
+    let mut in_section = false;
+    for (i, line) in text.lines().enumerate() {
+        let scalars = scalars(line);
+        if !line.starts_with([' ', '\t', '-', '#']) && !line.trim().is_empty() {
+            in_section = scalars.first().is_some_and(|(key, _)| ENTRY_SECTIONS.contains(key));
+        }
+        if in_section && let Some((_, column)) = scalars.iter().find(|(scalar, _)| *scalar == entry) {
+            return Some((i + 1, column + 1));
+        }
+    }
+    None
+}
+
+/// Keys and values of a YAML line with their byte offsets, without quotes.
+/// Block and flow syntax, like `- `, `key: ` and `[a, b]`, is skipped.
+fn scalars(line: &str) -> Vec<(&str, usize)> {
+    let bytes = line.as_bytes();
+    let is_break = |i: usize| i >= bytes.len() || bytes[i] == b' ' || bytes[i] == b'\t';
+    let ends_plain = |i: usize| match bytes[i] {
+        b':' => is_break(i + 1),
+        b'#' => bytes[i - 1] == b' ',
+        b',' | b']' | b'}' => true,
+        _ => false,
+    };
+    let mut scalars = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b' ' | b'\t' | b'[' | b']' | b'{' | b'}' | b',' => i += 1,
+            b'-' | b':' if is_break(i + 1) => i += 1,
+            b'#' => break,
+            quote @ (b'"' | b'\'') => {
+                let Some(length) = line[i + 1..].find(quote as char) else { break };
+                scalars.push((&line[i + 1..i + 1 + length], i + 1));
+                i += length + 2;
+            }
+            _ => {
+                let start = i;
+                while i < bytes.len() && !ends_plain(i) {
+                    i += 1;
+                }
+                scalars.push((line[start..i].trim_end(), start));
+            }
+        }
+    }
+    scalars
+}
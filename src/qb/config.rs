@@ -1,16 +1,149 @@
-use std::{collections::HashMap, env, io, path::{Path, PathBuf}};
-use crate::{fd, qb::{error::QboxError}};
-use serde::Deserialize;
+use std::{collections::{BTreeMap, HashMap}, env, path::{Path, PathBuf}};
+use crate::{fd, qb::{backup::BackupPolicy, error::QboxError, manifest::Mapping}};
+use serde::{Deserialize, Serialize};
 
 
-const CONFIG_VARIABLES: [&str; 1] = ["HOME"];
+/// XDG base directories and their defaults relative to `$HOME`, as the XDG base directory spec sets them.
+const XDG_DEFAULTS: [(&str, &str); 4] = [
+    ("XDG_CONFIG_HOME", ".config"),
+    ("XDG_DATA_HOME", ".local/share"),
+    ("XDG_STATE_HOME", ".local/state"),
+    ("XDG_CACHE_HOME", ".cache"),
+];
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[derive(Default)]
 pub struct Config {
     pub make_dir: bool,
-    pub files: Vec<HashMap<PathBuf, String>>,
+    pub files: Vec<FileEntry>,
     pub excludes: Vec<PathBuf>,
+    #[serde(default)]
+    pub backup: BackupPolicy,
+    #[serde(default)]
+    pub preserve: Preserve,
+    /// User variables, used in paths the same way as environment variables and taking precedence over them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
+    /// Shared config files merged under this one, relative to the directory of this file or absolute.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
+    /// Patterns of files that are stored encrypted, in the same form as `excludes`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub encrypt: Vec<String>,
+    /// File with the key of encrypted files, used when neither `QBOX_KEY_FILE` nor `QBOX_PASSPHRASE` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+    /// Overlays merged onto the config on the host with the same name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, Overlay>,
+    /// Overlays merged onto the config when the profile is selected.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Overlay>,
+}
+
+/// A config file included by `include`.
+/// It brings mappings, excludes and variables, and may include other files.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fragment {
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
+    files: Vec<FileEntry>,
+    #[serde(default)]
+    excludes: Vec<PathBuf>,
+    #[serde(default)]
+    vars: BTreeMap<String, String>,
+}
+
+/// Changes of the config for a host or a profile, see `Config::merge_overlay` for the rules.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Overlay {
+    pub make_dir: Option<bool>,
+    #[serde(default)]
+    pub files: Vec<FileEntry>,
+    #[serde(default)]
+    pub excludes: Vec<PathBuf>,
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum FileEntry {
+    /// A mapping with options:
+    /// `{ source: /path, target: /path, links: follow, include: ["*.conf"], max_size: 1024, max_depth: 2 }`.
+    Mapping(FileMapping),
+    /// `source: target` pairs without options, the original form.
+    Paths(HashMap<PathBuf, String>),
+}
+
+/// Source directory or file mapped to a target path.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileMapping {
+    pub source: PathBuf,
+    /// Target path, `*` means the source path itself.
+    pub target: String,
+    #[serde(default)]
+    pub links: LinkMode,
+    /// Gitignore-style patterns relative to the mapping root, in addition to the qbox excludes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excludes: Vec<String>,
+    /// Only files matching any of these patterns are taken, all files if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Files larger than this many bytes are skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// How deep under the mapping root files are taken, 1 is the root directory only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<usize>,
+    /// All files of the mapping are templates, not only the ones with the `.tmpl` suffix.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub template: bool,
+    /// All files of the mapping are stored encrypted.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypt: bool,
+}
+
+impl FileMapping {
+    pub fn new(source: PathBuf, target: String) -> Self {
+        Self { source, target, links: LinkMode::default(), excludes: Vec::new(), include: Vec::new(), max_size: None, max_depth: None, template: false, encrypt: false }
+    }
+
+    pub fn paths(&self) -> Mapping {
+        Mapping { source: self.source.clone(), target: self.target.clone() }
+    }
+}
+
+/// How symlinks inside a mapping are recorded.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// Symlinks are recorded as links and restored as links.
+    #[default]
+    Preserve,
+    /// Symlinks are followed and the files they point to are recorded.
+    Follow,
+}
+
+/// File attributes restored by `apply`, the `preserve` section of qbox.yaml.
+/// All attributes are preserved by default.
+/// The owner is only restored when qbox runs with the privileges to change it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Preserve {
+    pub mode: bool,
+    pub owner: bool,
+    pub times: bool,
+}
+
+impl Default for Preserve {
+    fn default() -> Self {
+        Self { mode: true, owner: true, times: true }
+    }
 }
 
 
@@ -19,98 +152,307 @@ impl Config {
         Self::default()
     }
 
+    /// Merges the overlay of the host and then the overlay of the profile onto the config,
+    /// so the profile takes precedence. The host overlay is looked up by the full hostname
+    /// and by its first label. A profile that is not defined is an error.
+    pub fn apply_overlays(&mut self, hostname: &str, profile: Option<&str>) -> Result<(), QboxError> {
+        let short_hostname = hostname.split('.').next().unwrap_or(hostname);
+        let host_overlay = self.hosts.get(hostname).or_else(|| self.hosts.get(short_hostname)).cloned();
+        if let Some(overlay) = host_overlay {
+            self.merge_overlay(&overlay);
+        }
+        if let Some(profile) = profile {
+            let Some(overlay) = self.profiles.get(profile).cloned() else {
+                return Err(QboxError::UnknownProfile(profile.to_string()));
+            };
+            self.merge_overlay(&overlay);
+        }
+        Ok(())
+    }
+
+    /// Merges the overlay onto the config:
+    /// `make_dir` is replaced if the overlay sets it;
+    /// a mapping of the overlay replaces the mapping with the same source, other mappings are added;
+    /// excludes are added after the existing ones, so `!pattern` can take a file back;
+    /// variables are replaced or added.
+    pub fn merge_overlay(&mut self, overlay: &Overlay) {
+        if let Some(make_dir) = overlay.make_dir {
+            self.make_dir = make_dir;
+        }
+        if !overlay.files.is_empty() {
+            let overlay_config = Config { files: overlay.files.clone(), ..Default::default() };
+            let overlay_mappings = overlay_config.mappings();
+            let mut files: Vec<FileEntry> = self.mappings().into_iter()
+                .filter(|m| !overlay_mappings.iter().any(|o| o.source == m.source))
+                .map(FileEntry::Mapping)
+                .collect();
+            files.extend(overlay_mappings.into_iter().map(FileEntry::Mapping));
+            self.files = files;
+        }
+        self.excludes.extend(overlay.excludes.iter().cloned());
+        self.vars.extend(overlay.vars.clone());
+    }
+
+    /// Unset or empty XDG base directories get their default under `$HOME`.
     pub fn variable_data(variable: &str) -> Result<String, QboxError> {
-        match variable {
-            "HOME" => {
-                let home = env::var("HOME")?;
-                Ok(home)
-            },
-            _ => Err(
-                QboxError::ConfigUndefinedVariable(variable.to_string())
-            ),
+        let value = match env::var(variable) {
+            Ok(value) => Some(value),
+            Err(env::VarError::NotPresent) => None,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some((_, default)) = XDG_DEFAULTS.iter().find(|(name, _)| *name == variable)
+            && value.as_deref().is_none_or(str::is_empty) {
+                return Ok(format!("{}/{}", env::var("HOME")?, default));
+            }
+        value.ok_or_else(|| QboxError::ConfigUndefinedVariable(variable.to_string()))
+    }
+
+    /// Value of a user variable or of an environment variable, `None` if the variable is not defined.
+    /// `resolving` holds the user variables being expanded, to catch variables that refer to themselves.
+    fn variable(&self, name: &str, resolving: &mut Vec<String>) -> Result<Option<String>, QboxError> {
+        if let Some(value) = self.vars.get(name) {
+            if resolving.iter().any(|r| r == name) {
+                return Err(QboxError::VariableCycle(name.to_string()));
+            }
+            resolving.push(name.to_string());
+            let value = self.expand(value, resolving)?;
+            resolving.pop();
+            return Ok(Some(value));
+        }
+        match Config::variable_data(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(QboxError::ConfigUndefinedVariable(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(crate) fn expanded_vars(&self) -> Result<BTreeMap<String, String>, QboxError> {
+        let mut vars = BTreeMap::new();
+        for name in self.vars.keys() {
+            if let Some(value) = self.variable(name, &mut Vec::new())? {
+                vars.insert(name.clone(), value);
+            }
+        }
+        Ok(vars)
+    }
+
+    /// Expands `~` at the start of the text, `$VAR`, `${VAR}` and `${VAR:-default}`.
+    /// The default is used when the variable is not defined or empty.
+    /// A `$` that does not start a variable is kept as is.
+    fn expand(&self, text: &str, resolving: &mut Vec<String>) -> Result<String, QboxError> {
+        let mut result = String::new();
+        let mut rest = text;
+        if rest == "~" || rest.starts_with("~/") {
+            result.push_str(&Config::variable_data("HOME")?);
+            rest = &rest[1..];
+        }
+        while let Some(dollar_pos) = rest.find('$') {
+            result.push_str(&rest[..dollar_pos]);
+            let after = &rest[dollar_pos + 1..];
+            if let Some(braced) = after.strip_prefix('{') {
+                let Some(end) = closing_brace(braced) else {
+                    return Err(QboxError::InvalidVariableSyntax(rest[dollar_pos..].to_string()));
+                };
+                let (name, default) = match braced[..end].split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (&braced[..end], None),
+                };
+                match (self.variable(name, resolving)?, default) {
+                    (Some(value), Some(_)) if !value.is_empty() => result.push_str(&value),
+                    (_, Some(default)) => result.push_str(&self.expand(default, resolving)?),
+                    (Some(value), None) => result.push_str(&value),
+                    (None, None) => return Err(QboxError::ConfigUndefinedVariable(name.to_string())),
+                }
+                rest = &braced[end + 1..];
+            } else {
+                let name_len = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+                if name_len == 0 || after.starts_with(|c: char| c.is_ascii_digit()) {
+                    result.push('$');
+                    rest = after;
+                    continue;
+                }
+                let name = &after[..name_len];
+                match self.variable(name, resolving)? {
+                    Some(value) => result.push_str(&value),
+                    None => return Err(QboxError::ConfigUndefinedVariable(name.to_string())),
+                }
+                rest = &after[name_len..];
+            }
         }
+        result.push_str(rest);
+        Ok(result)
     }
 
     pub fn validate(&mut self) -> Result<(), QboxError> {
-        let mut valid_files: Vec<HashMap<PathBuf, String>> = Vec::new();
+        let mut valid_files: Vec<FileEntry> = Vec::new();
         for file in &self.files {
-            let mut valid_map: HashMap<PathBuf, String> = HashMap::new();
-            for (source_path, target_path) in file {
-                self.validate_path_style(target_path, &source_path.to_string_lossy())?;
-                let valid_source_path = self.format_path(source_path, true)?;
-                let valid_target_path = if target_path == "*" {
-                    valid_source_path.to_string_lossy().to_string()
-                } else if self.make_dir{
-                    self.format_path(Path::new(target_path), false)?.to_string_lossy().to_string()
-                } else {
-                    self.format_path(Path::new(target_path), true)?.to_string_lossy().to_string()
-                };
-                valid_map.insert(valid_source_path, valid_target_path);
+            match file {
+                FileEntry::Paths(paths) => {
+                    let mut valid_map: HashMap<PathBuf, String> = HashMap::new();
+                    for (source_path, target_path) in paths {
+                        let (valid_source_path, valid_target_path) = self.validate_mapping(source_path, target_path)?;
+                        valid_map.insert(valid_source_path, valid_target_path);
+                    }
+                    valid_files.push(FileEntry::Paths(valid_map));
+                }
+                FileEntry::Mapping(mapping) => {
+                    let (source, target) = self.validate_mapping(&mapping.source, &mapping.target)?;
+                    valid_files.push(FileEntry::Mapping(FileMapping { source, target, ..mapping.clone() }));
+                }
             }
-            valid_files.push(valid_map);
         }
         self.files = valid_files;
         self.format_exclude_paths()?;
         Ok(())
     }
 
-    /// Path formatting. Checking path style and applying variables.
-    fn format_path(&self, path: &Path, check_exists: bool) -> Result<PathBuf, QboxError>{
-        let system_file_path = path.to_string_lossy();
-        if let Some(dollar_pos) = system_file_path.find("$")
-            && let Some(slash_pos) = system_file_path[dollar_pos..].find("/"){
-                let variable = &system_file_path[dollar_pos+1..dollar_pos+1 + slash_pos-1];
-                if CONFIG_VARIABLES.contains(&variable){
-                    let var_data = Config::variable_data(variable)?;
-                    let new_path = PathBuf::from(system_file_path[..dollar_pos].trim())
-                        .join(var_data)
-                        .join(system_file_path[dollar_pos + slash_pos..].trim_start_matches('/'));
-                    if check_exists{
-                        fd::dir::path_exists(&new_path)?;
-                    }
-                    Ok(new_path)
-                } else {
-                    Err(QboxError::ConfigUndefinedVariable(variable.to_string()))
-                }
+    fn validate_mapping(&self, source_path: &Path, target_path: &str) -> Result<(PathBuf, String), QboxError> {
+        // Variables are applied first, a path like `~/dir` is absolute once expanded.
+        let valid_source_path = self.format_path(source_path, false)?;
+        let valid_target_path = if target_path == "*" {
+            target_path.to_string()
         } else {
-            if check_exists{
-                fd::dir::path_exists(path)?;
-            }
-            Ok(path.to_path_buf())
+            self.format_path(Path::new(target_path), false)?.to_string_lossy().to_string()
+        };
+        self.validate_path_style(&valid_target_path, &valid_source_path.to_string_lossy())?;
+        check_exists(&valid_source_path)?;
+        if target_path == "*" {
+            return Ok((valid_source_path.clone(), valid_source_path.to_string_lossy().to_string()));
+        }
+        if !self.make_dir {
+            check_exists(Path::new(&valid_target_path))?;
         }
+        Ok((valid_source_path, valid_target_path))
+    }
+
+    /// All source to target mappings of the config.
+    /// The mappings are sorted by source so that the order does not depend on the hash map.
+    pub fn mappings(&self) -> Vec<FileMapping> {
+        let mut mappings: Vec<FileMapping> = self.files.iter()
+            .flat_map(|file| match file {
+                FileEntry::Paths(paths) => paths.iter()
+                    .map(|(source, target)| FileMapping::new(source.clone(), target.clone()))
+                    .collect(),
+                FileEntry::Mapping(mapping) => vec![mapping.clone()],
+            })
+            .collect();
+        mappings.sort_by(|a, b| a.source.cmp(&b.source));
+        mappings
+    }
+
+    /// Applies variables to the mapping and returns the source and target paths.
+    /// The paths are not required to exist.
+    pub fn resolve_mapping(&self, mapping: &Mapping) -> Result<(PathBuf, PathBuf), QboxError> {
+        let source = self.format_path(&mapping.source, false)?;
+        let target = if mapping.target == "*" {
+            source.clone()
+        } else {
+            self.format_path(Path::new(&mapping.target), false)?
+        };
+        Ok((source, target))
+    }
+
+    /// Path formatting. Applying variables and checking that the path exists.
+    /// Slashes doubled by a variable in the middle of the path, as in `/$HOME/dir`, are collapsed.
+    pub(crate) fn format_path(&self, path: &Path, check_exists: bool) -> Result<PathBuf, QboxError>{
+        let mut expanded = self.expand(&path.to_string_lossy(), &mut Vec::new())?;
+        while expanded.contains("//") {
+            expanded = expanded.replace("//", "/");
+        }
+        let new_path = PathBuf::from(expanded);
+        if check_exists{
+            fd::dir::path_exists(&new_path)?;
+        }
+        Ok(new_path)
     }
 
     fn format_exclude_paths(&mut self) -> Result<(), QboxError> {
         let mut valid_excludes: Vec<PathBuf> = Vec::new();
         for exclude_path in &self.excludes {
-            valid_excludes.push(self.format_path(exclude_path, true)?);
+            valid_excludes.push(self.format_path(exclude_path, false)?);
         }
         self.excludes = valid_excludes;
         Ok(())
     }
 
-    pub fn excludes_to_str(&self) -> Vec<&str>{
-        self.excludes.iter().map(|p| p.to_str().expect("invalid utf-8 in exclude path")).collect()
-    }
-
     fn validate_path_style(&self, target_path: &str, source_path: &str) -> Result<(), QboxError>{
-        if target_path != "*" && (!source_path.starts_with("/") || !target_path.starts_with("/")) {
-            return Err(QboxError::IO(
-                io::Error::new(io::ErrorKind::NotFound, "config path must be in absolute format"))
-            );
-        }
-        if target_path != "*" && (source_path.ends_with("/") || target_path.ends_with("/")) {
-            return Err(QboxError::IO(
-                io::Error::new(io::ErrorKind::NotFound, "config path must not end with '/'"))
-            );
+        for path in [source_path, target_path] {
+            if target_path == "*" {
+                break;
+            }
+            if !path.starts_with("/") {
+                return Err(QboxError::InvalidConfigPath { entry: path.to_string(), reason: "path must be in absolute format".to_string() });
+            }
+            if path.ends_with("/") {
+                return Err(QboxError::InvalidConfigPath { entry: path.to_string(), reason: "path must not end with '/'".to_string() });
+            }
         }
         Ok(())
     }
 
 }
 
+/// Reads the config together with the files it includes.
+/// Included files are merged first, in the order they are listed, and the config itself on top of them,
+/// with the rules of `Config::merge_overlay`. An included file that includes its caller is an error.
 pub fn read_config(path: PathBuf) -> Result<Config, QboxError>{
-    let content = std::fs::read_to_string(path)?;
+    let content = std::fs::read_to_string(&path)?;
     let cfg: Config = serde_yaml::from_str(&content)?;   
-    Ok(cfg)
-}
\ No newline at end of file
+    if cfg.include.is_empty() {
+        return Ok(cfg);
+    }
+    let mut merged = Config { files: Vec::new(), excludes: Vec::new(), vars: BTreeMap::new(), ..cfg.clone() };
+    let mut stack = vec![path.canonicalize()?];
+    for include in &cfg.include {
+        merge_include(&mut merged, &path, include, &mut stack)?;
+    }
+    merged.merge_overlay(&Overlay { make_dir: None, files: cfg.files, excludes: cfg.excludes, vars: cfg.vars });
+    Ok(merged)
+}
+
+/// Merges the included file, and the files it includes before it, into the config.
+/// `stack` holds the files being included, to catch cycles.
+fn merge_include(config: &mut Config, including: &Path, include: &Path, stack: &mut Vec<PathBuf>) -> Result<(), QboxError> {
+    let include = Config::new().format_path(include, false)?;
+    let path = match including.parent() {
+        Some(dir) if include.is_relative() => dir.join(include),
+        _ => include,
+    };
+    let in_file = |e: QboxError| QboxError::IncludedConfig(path.clone(), Box::new(e));
+    let canonical = path.canonicalize().map_err(|e| in_file(e.into()))?;
+    if stack.contains(&canonical) {
+        let mut cycle = stack.clone();
+        cycle.push(canonical);
+        return Err(QboxError::IncludeCycle(cycle));
+    }
+    let content = std::fs::read_to_string(&path).map_err(|e| in_file(e.into()))?;
+    let fragment: Fragment = serde_yaml::from_str(&content).map_err(|e| in_file(e.into()))?;
+    stack.push(canonical);
+    for nested in &fragment.include {
+        merge_include(config, &path, nested, stack)?;
+    }
+    stack.pop();
+    config.merge_overlay(&Overlay { make_dir: None, files: fragment.files, excludes: fragment.excludes, vars: fragment.vars });
+    Ok(())
+}
+
+fn check_exists(path: &Path) -> Result<(), QboxError> {
+    if !path.exists() {
+        return Err(QboxError::InvalidConfigPath { entry: path.display().to_string(), reason: "path does not exist".to_string() });
+    }
+    Ok(())
+}
+
+/// Position of the brace closing a `${...}` variable, nested variables in the default included.
+fn closing_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
@@ -0,0 +1,134 @@
+use std::{cell::RefCell, collections::HashMap, env, fmt, fs, path::Path};
+use ::ignore::gitignore::Gitignore;
+use argon2::Argon2;
+use chacha20poly1305::{aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng}, Key, XChaCha20Poly1305, XNonce};
+use crate::qb::{config::{Config, FileMapping}, error::QboxError, ignore::pattern_matcher, manifest::ManifestEntry, qbox::Qbox};
+
+/// Environment variable with the passphrase of encrypted files.
+pub const PASSPHRASE_VAR: &str = "QBOX_PASSPHRASE";
+/// Environment variable with the path of a key file, it takes precedence over the passphrase.
+pub const KEY_FILE_VAR: &str = "QBOX_KEY_FILE";
+/// Start of every encrypted object, followed by the salt, the nonce and the ciphertext.
+const MAGIC: &[u8] = b"qbox-encrypted-1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Which files of a mapping are stored encrypted: all of them if the mapping is marked
+/// with `encrypt: true`, otherwise the files matching the `encrypt` patterns of the config.
+#[derive(Debug)]
+pub struct Secrets {
+    all: bool,
+    patterns: Gitignore,
+}
+
+impl Secrets {
+    pub fn new(config: &Config, mapping: &FileMapping, source: &Path) -> Result<Self, QboxError> {
+        let mut patterns = Vec::new();
+        for pattern in &config.encrypt {
+            patterns.push(config.format_path(Path::new(pattern), false)?.to_string_lossy().to_string());
+        }
+        Ok(Self { all: mapping.encrypt, patterns: pattern_matcher(&patterns, source)? })
+    }
+
+    pub fn is_encrypted(&self, relative: &Path) -> bool {
+        self.all || self.patterns.matched_path_or_any_parents(relative, false).is_ignore()
+    }
+}
+
+/// Encrypts objects with XChaCha20-Poly1305 under a key derived with Argon2
+/// from the passphrase or the key file. Every object carries the salt of its key,
+/// so it can be decrypted with the passphrase alone. A key is derived once per salt.
+pub struct Cipher {
+    secret: Vec<u8>,
+    salt: [u8; SALT_LEN],
+    keys: RefCell<HashMap<[u8; SALT_LEN], Key>>,
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher").finish_non_exhaustive()
+    }
+}
+
+impl Cipher {
+    pub fn new(secret: Vec<u8>) -> Self {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self { secret, salt, keys: RefCell::new(HashMap::new()) }
+    }
+
+    /// Reads the secret from the key file of `QBOX_KEY_FILE`, from `QBOX_PASSPHRASE`
+    /// or from the key file of the config, in this order.
+    pub fn from_env(config: &Config) -> Result<Self, QboxError> {
+        let secret = if let Ok(key_file) = env::var(KEY_FILE_VAR) {
+            fs::read(key_file)?
+        } else if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+            passphrase.into_bytes()
+        } else if let Some(key_file) = &config.key_file {
+            fs::read(config.format_path(key_file, false)?)?
+        } else {
+            return Err(QboxError::MissingKey);
+        };
+        if secret.is_empty() {
+            return Err(QboxError::MissingKey);
+        }
+        Ok(Self::new(secret))
+    }
+
+    fn key(&self, salt: &[u8; SALT_LEN]) -> Key {
+        if let Some(key) = self.keys.borrow().get(salt) {
+            return *key;
+        }
+        let mut key = Key::default();
+        Argon2::default().hash_password_into(&self.secret, salt, &mut key).expect("salt and key lengths are valid for argon2");
+        self.keys.borrow_mut().insert(*salt, key);
+        key
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&self.key(&self.salt))
+            .encrypt(&nonce, plaintext)
+            .expect("content fits into a single message");
+        [MAGIC, &self.salt, &nonce, &ciphertext].concat()
+    }
+
+    /// Decrypts the object, `path` is the file it belongs to, for errors.
+    pub fn decrypt(&self, data: &[u8], path: &Path) -> Result<Vec<u8>, QboxError> {
+        let header_len = MAGIC.len() + SALT_LEN + NONCE_LEN;
+        if data.len() < header_len || !data.starts_with(MAGIC) {
+            return Err(QboxError::Decrypt(path.to_path_buf()));
+        }
+        let salt: [u8; SALT_LEN] = data[MAGIC.len()..MAGIC.len() + SALT_LEN].try_into().expect("salt has its length");
+        let nonce = XNonce::from_slice(&data[MAGIC.len() + SALT_LEN..header_len]);
+        XChaCha20Poly1305::new(&self.key(&salt))
+            .decrypt(nonce, &data[header_len..])
+            .map_err(|_| QboxError::Decrypt(path.to_path_buf()))
+    }
+}
+
+impl Qbox {
+    /// The cipher of encrypted files. The key is read when it is needed for the first time,
+    /// a qbox without encrypted files works without one.
+    pub(crate) fn cipher(&self) -> Result<&Cipher, QboxError> {
+        if let Some(cipher) = self.cipher.get() {
+            return Ok(cipher);
+        }
+        let cipher = Cipher::from_env(&self.config)?;
+        Ok(self.cipher.get_or_init(|| cipher))
+    }
+
+    pub(crate) fn secrets(&self, mapping: &FileMapping) -> Result<Secrets, QboxError> {
+        let (source, _) = self.config.resolve_mapping(&mapping.paths())?;
+        Secrets::new(&self.config, mapping, &source)
+    }
+
+    /// Content of the object of the entry, decrypted in memory if the entry is encrypted.
+    pub(crate) fn object_content(&self, entry: &ManifestEntry) -> Result<Vec<u8>, QboxError> {
+        let data = fs::read(self.store.object_path(&entry.hash))?;
+        if !entry.encrypted {
+            return Ok(data);
+        }
+        self.cipher()?.decrypt(&data, &entry.path)
+    }
+}
@@ -0,0 +1,121 @@
+use std::{collections::{BTreeMap, BTreeSet}, fmt, fs, path::{Path, PathBuf}};
+use similar::TextDiff;
+use crate::qb::error::QboxError;
+
+/// How a file changes when going from the old side of a comparison to the new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffStatus {
+    Added,
+    Removed,
+    Modified,
+    Unchanged,
+}
+
+impl fmt::Display for DiffStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffStatus::Added => write!(f, "added"),
+            DiffStatus::Removed => write!(f, "removed"),
+            DiffStatus::Modified => write!(f, "modified"),
+            DiffStatus::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffSide {
+    pub hash: String,
+    /// The content of the file, `None` if it has no text content (a symlink).
+    pub content: Option<Content>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Content {
+    File(PathBuf),
+    Rendered(String),
+    /// Decrypted content of an encrypted object, kept in memory only.
+    Decrypted(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub struct FileDiff {
+    pub path: PathBuf,
+    pub status: DiffStatus,
+    /// Unified diff of the file, only for changed text files and only if it was requested.
+    pub unified: Option<String>,
+}
+
+impl FileDiff {
+    pub fn is_changed(&self) -> bool {
+        self.status != DiffStatus::Unchanged
+    }
+}
+
+impl fmt::Display for FileDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>9} {}", self.status, self.path.display())?;
+        if let Some(unified) = &self.unified {
+            write!(f, "\n{}", unified)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares two sets of files keyed by path.
+/// Files are considered equal if their hashes are equal.
+pub fn compare(
+    old: &BTreeMap<PathBuf, DiffSide>,
+    new: &BTreeMap<PathBuf, DiffSide>,
+    unified: bool,
+) -> Result<Vec<FileDiff>, QboxError> {
+    let paths: BTreeSet<&PathBuf> = old.keys().chain(new.keys()).collect();
+    let mut diffs = Vec::new();
+    for path in paths {
+        let (old_side, new_side) = (old.get(path), new.get(path));
+        let status = match (old_side, new_side) {
+            (Some(o), Some(n)) if o.hash == n.hash => DiffStatus::Unchanged,
+            (Some(_), Some(_)) => DiffStatus::Modified,
+            (None, Some(_)) => DiffStatus::Added,
+            _ => DiffStatus::Removed,
+        };
+        let unified_text = if unified && status != DiffStatus::Unchanged {
+            unified_diff(path, old_side, new_side)?
+        } else {
+            None
+        };
+        diffs.push(FileDiff { path: path.clone(), status, unified: unified_text });
+    }
+    Ok(diffs)
+}
+
+/// Unified diff between two files, a missing file is treated as empty.
+/// Returns `None` if either of the files is not text.
+fn unified_diff(path: &Path, old: Option<&DiffSide>, new: Option<&DiffSide>) -> Result<Option<String>, QboxError> {
+    let (Some(old_text), Some(new_text)) = (read_text(old)?, read_text(new)?) else {
+        return Ok(None);
+    };
+    let name = path.to_string_lossy();
+    let text = TextDiff::from_lines(&old_text, &new_text)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a{}", name), &format!("b{}", name))
+        .to_string();
+    Ok(Some(text))
+}
+
+/// Reads the file as text. A file is text if it is valid utf-8 without NUL bytes.
+fn read_text(side: Option<&DiffSide>) -> Result<Option<String>, QboxError> {
+    let Some(side) = side else {
+        return Ok(Some(String::new()));
+    };
+    let data = match &side.content {
+        Some(Content::File(path)) => fs::read(path)?,
+        Some(Content::Rendered(text)) => text.clone().into_bytes(),
+        Some(Content::Decrypted(data)) => data.clone(),
+        None => return Ok(None),
+    };
+    if data.contains(&0) {
+        return Ok(None);
+    }
+    Ok(String::from_utf8(data).ok())
+}
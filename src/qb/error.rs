@@ -3,26 +3,122 @@ use std::{env, error, fmt, io, path::PathBuf};
 #[derive(Debug)]
 pub enum QboxError {
     MissingQbox(PathBuf),
+    /// The boxes directory does not exist, `qb init` was not run.
+    MissingBoxes(PathBuf),
+    BoxExists(PathBuf),
+    /// A qbox or version name that is not a single plain path component.
+    InvalidName(String),
+    /// A version name git does not accept as a reference, in a git-backed qbox.
+    InvalidRefName(String),
     MissingConfig(PathBuf),
-    VersionPathError(PathBuf, String),
+    CreateDir { path: PathBuf, source: io::Error },
+    VersionExists(PathBuf),
+    VersionMissing(PathBuf),
+    /// The version has no manifest.
+    VersionNotRecorded(PathBuf),
+    /// The version holds the plain copies of an older qbox instead of a manifest.
+    LegacyVersion(PathBuf),
+    /// The version has content and can only be deleted with `force`.
+    VersionNotEmpty(PathBuf),
+    BackupMissing(PathBuf),
+    InvalidArchive { path: PathBuf, reason: String },
     ConfigParse(serde_yaml::Error),
+    InvalidConfigPath { entry: String, reason: String },
     ConfigUndefinedVariable(String),
+    /// A `${` without its closing brace, with the rest of the text from it.
+    InvalidVariableSyntax(String),
+    /// A user variable that refers to itself, directly or through other variables.
+    VariableCycle(String),
     Variable(env::VarError),
     ReservedKeyword(String),
+    /// An included config file failed to be read.
+    IncludedConfig(PathBuf, Box<QboxError>),
+    /// Config files that include each other, the first file is included again by the last one.
+    IncludeCycle(Vec<PathBuf>),
+    UnknownProfile(String),
+    Pattern(ignore::Error),
+    Template(minijinja::Error),
+    NotApplied(PathBuf),
+    /// Target paths that are taken by files not owned by the qbox.
+    LinkConflict(Vec<PathBuf>),
+    /// The qbox is linked to another version, it has to be unlinked first.
+    AlreadyLinked(String),
+    NotLinked(PathBuf),
+    /// Encrypted files can not be linked, the working copy would hold them in plaintext.
+    EncryptedLink(PathBuf),
+    /// Neither a passphrase nor a key file is given for encrypted files.
+    MissingKey,
+    /// The object of the file can not be decrypted, the key is wrong or the object is damaged.
+    Decrypt(PathBuf),
     IO(io::Error),
+    Git { args: String, message: String },
+    /// The git binary is not found, a git-backed qbox can not be used without it.
+    GitMissing,
+    CopyFailed { src: PathBuf, dst: PathBuf, source: io::Error },
+    /// The operation failed, the changed paths were restored.
+    RolledBack(Box<QboxError>, Vec<PathBuf>),
+    /// The operation failed and so did the rollback. The snapshot of the original files is kept.
+    RollbackFailed(Box<QboxError>, Box<QboxError>, PathBuf),
 }
 
 impl fmt::Display for QboxError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             QboxError::MissingQbox(path) => write!(f, "qbox dir not found: {}", path.display()),
+            QboxError::MissingBoxes(path) => write!(f, "boxes directory not found: {}, run qb init first", path.display()),
+            QboxError::BoxExists(path) => write!(f, "qbox already exists: {}", path.display()),
+            QboxError::InvalidName(name) => write!(f, "invalid name {:?}: it must not contain '/', '..' or NUL, nor start with '.'", name),
+            QboxError::InvalidRefName(name) => write!(f, "invalid version name {:?}: git does not accept it as a reference name", name),
             QboxError::MissingConfig(path) => write!(f, "config file not found: {}", path.display()),
-            QboxError::VersionPathError(path, err) => write!(f, "version {} path error: {}", path.display(), err),
+            QboxError::CreateDir { path, source } => write!(f, "error creating directory {}: {}", path.display(), source),
+            QboxError::VersionExists(path) => write!(f, "version already exists: {}", path.display()),
+            QboxError::VersionMissing(path) => write!(f, "version not found: {}", path.display()),
+            QboxError::VersionNotRecorded(path) => write!(f, "version is not recorded: {}", path.display()),
+            QboxError::LegacyVersion(path) => write!(f, "version {} was recorded by an older qbox, delete it and record it again", path.display()),
+            QboxError::VersionNotEmpty(path) => write!(f, "version is not empty, delete it with --force: {}", path.display()),
+            QboxError::BackupMissing(path) => write!(f, "backup not found: {}", path.display()),
+            QboxError::InvalidArchive { path, reason } => write!(f, "invalid archive {}: {}", path.display(), reason),
+            QboxError::InvalidConfigPath { entry, reason } => write!(f, "invalid config path {}: {}", entry, reason),
             QboxError::ConfigUndefinedVariable(variable) => write!(f, "undefined variable {}", variable),
-            QboxError::Variable(e) => write!(f, "wariable error: {}", e),
+            QboxError::InvalidVariableSyntax(text) => write!(f, "variable {} has no closing brace", text),
+            QboxError::VariableCycle(variable) => write!(f, "variable {} refers to itself", variable),
+            QboxError::Variable(e) => write!(f, "variable error: {}", e),
             QboxError::ConfigParse(e) => write!(f, "parse config error: {}", e),
             QboxError::ReservedKeyword(name) => write!(f, "keyword {} is reserved", name),
+            QboxError::IncludedConfig(path, e) => write!(f, "included config {}: {}", path.display(), e),
+            QboxError::IncludeCycle(paths) => {
+                let chain: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "config include cycle: {}", chain.join(" -> "))
+            }
+            QboxError::UnknownProfile(name) => write!(f, "profile {} is not defined", name),
+            QboxError::Pattern(e) => write!(f, "invalid pattern: {}", e),
+            QboxError::Template(e) => write!(f, "template error: {}", e),
+            QboxError::NotApplied(path) => write!(f, "no version was applied to {}", path.display()),
+            QboxError::LinkConflict(paths) => {
+                write!(f, "{} target path(s) are taken by files not owned by qbox", paths.len())?;
+                for path in paths {
+                    write!(f, "\n  conflict {}", path.display())?;
+                }
+                Ok(())
+            }
+            QboxError::AlreadyLinked(version) => write!(f, "version {} is linked, unlink it first", version),
+            QboxError::NotLinked(path) => write!(f, "no version is linked to {}", path.display()),
+            QboxError::EncryptedLink(path) => write!(f, "encrypted file {} can not be linked", path.display()),
+            QboxError::MissingKey => write!(f, "no key for encrypted files, set QBOX_PASSPHRASE, QBOX_KEY_FILE or key_file in the config"),
+            QboxError::Decrypt(path) => write!(f, "can not decrypt {}, the key is wrong or the object is damaged", path.display()),
             QboxError::IO(e) => write!(f, "io error: {}", e),
+            QboxError::Git { args, message } => write!(f, "git {} failed: {}", args, message),
+            QboxError::GitMissing => write!(f, "git is not installed or not in PATH, it is needed by git-backed qboxes"),
+            QboxError::CopyFailed { src, dst, source } => write!(f, "error copying {} to {}: {}", src.display(), dst.display(), source),
+            QboxError::RolledBack(e, restored) => {
+                write!(f, "{}; rolled back {} path(s)", e, restored.len())?;
+                for path in restored {
+                    write!(f, "\n  restored {}", path.display())?;
+                }
+                Ok(())
+            }
+            QboxError::RollbackFailed(e, rollback_error, snapshot) =>
+                write!(f, "{}; rollback failed: {}; original files are kept in {}", e, rollback_error, snapshot.display()),
         }
     }
 }
@@ -32,11 +128,56 @@ impl error::Error for QboxError {
         match self {
             QboxError::ConfigParse(e) => Some(e),
             QboxError::Variable(e) => Some(e),
+            QboxError::IO(e) => Some(e),
+            QboxError::CreateDir { source, .. } | QboxError::CopyFailed { source, .. } => Some(source),
+            QboxError::Pattern(e) => Some(e),
+            QboxError::Template(e) => Some(e),
+            QboxError::IncludedConfig(_, e) => Some(e.as_ref()),
+            QboxError::RolledBack(e, _) => Some(e.as_ref()),
+            QboxError::RollbackFailed(e, _, _) => Some(e.as_ref()),
             _ => None,
         }
     }
 }
 
+impl QboxError {
+    /// Exit code of the cli for the error:
+    /// 2 config problems, 3 missing or existing qboxes and invalid names, 4 version, backup and archive problems,
+    /// 5 link conflicts, 6 rolled back operations, 7 failed rollbacks, 1 everything else.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            QboxError::MissingConfig(_)
+            | QboxError::ConfigParse(_)
+            | QboxError::InvalidConfigPath { .. }
+            | QboxError::ConfigUndefinedVariable(_)
+            | QboxError::InvalidVariableSyntax(_)
+            | QboxError::VariableCycle(_)
+            | QboxError::Variable(_)
+            | QboxError::IncludedConfig(..)
+            | QboxError::IncludeCycle(_)
+            | QboxError::UnknownProfile(_)
+            | QboxError::MissingKey
+            | QboxError::Pattern(_)
+            | QboxError::Template(_) => 2,
+            QboxError::MissingQbox(_) | QboxError::MissingBoxes(_) | QboxError::BoxExists(_) | QboxError::InvalidName(_) | QboxError::InvalidRefName(_) => 3,
+            QboxError::VersionExists(_)
+            | QboxError::VersionMissing(_)
+            | QboxError::VersionNotRecorded(_)
+            | QboxError::LegacyVersion(_)
+            | QboxError::VersionNotEmpty(_)
+            | QboxError::BackupMissing(_)
+            | QboxError::InvalidArchive { .. }
+            | QboxError::Decrypt(_)
+            | QboxError::ReservedKeyword(_)
+            | QboxError::NotApplied(_) => 4,
+            QboxError::LinkConflict(_) | QboxError::AlreadyLinked(_) | QboxError::NotLinked(_) | QboxError::EncryptedLink(_) => 5,
+            QboxError::RolledBack(..) => 6,
+            QboxError::RollbackFailed(..) => 7,
+            QboxError::CreateDir { .. } | QboxError::CopyFailed { .. } | QboxError::IO(_) | QboxError::Git { .. } | QboxError::GitMissing => 1,
+        }
+    }
+}
+
 impl From<serde_yaml::Error> for QboxError {
     fn from(err: serde_yaml::Error) -> Self {
         QboxError::ConfigParse(err)
@@ -0,0 +1,272 @@
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}, process::{Command, Stdio}, thread};
+use crate::qb::{error::QboxError, manifest::{Manifest, ManifestEntry, MANIFEST_NAME}, store::{ObjectStore, OBJECTS_DIR}, version_store::{VersionMetadata, VersionStore}};
+
+/// Git repository of a git-backed qbox, inside the qbox directory.
+pub const GIT_DIR: &str = ".git";
+/// Directory of a version commit with the content of the files, one subdirectory per mapping source.
+const FILES_DIR: &str = "files";
+/// Namespace of the version branches, apart from other branches so the repository can hold other work.
+const REFS_PREFIX: &str = "refs/heads/qbox/";
+const FALLBACK_IDENTITY: [(&str, &str); 4] = [
+    ("GIT_AUTHOR_NAME", "qbox"),
+    ("GIT_AUTHOR_EMAIL", "qbox@localhost"),
+    ("GIT_COMMITTER_NAME", "qbox"),
+    ("GIT_COMMITTER_EMAIL", "qbox@localhost"),
+];
+
+/// Versions kept in a git repository, each as the branch `qbox/<version>`.
+/// Every change of a version is a commit on its branch, so a record is a commit.
+/// The commit holds the files of the version, like the manifest, and the content
+/// of the recorded files under `files/<mapping source>/`, so the history of every file can be
+/// read with plain git, and the branches can be pushed and fetched like any other.
+/// The content is therefore kept twice, in the commits and in the object store: commands
+/// read the object store only, and objects missing from it, like those of a fetched
+/// version, are restored from the commit when the version is read.
+/// The work tree is not used, everything goes through git plumbing.
+#[derive(Debug)]
+pub struct GitStore {
+    qbox_path: PathBuf,
+}
+
+impl GitStore {
+    pub fn new(qbox_path: &Path) -> Self {
+        Self { qbox_path: qbox_path.to_path_buf() }
+    }
+
+    /// The files of the qbox directory are excluded, versions live in commits only.
+    pub fn init(qbox_path: &Path) -> Result<(), QboxError> {
+        run(Command::new("git").arg("init").arg("-q").arg(qbox_path), None)?;
+        let info = qbox_path.join(GIT_DIR).join("info");
+        fs::create_dir_all(&info).map_err(|source| QboxError::CreateDir { path: info.clone(), source })?;
+        fs::write(info.join("exclude"), "*\n")?;
+        Ok(())
+    }
+
+    fn git(&self) -> Command {
+        let mut command = Command::new("git");
+        command.arg("--git-dir").arg(self.qbox_path.join(GIT_DIR));
+        command
+    }
+
+    fn text(&self, args: &[&str]) -> Result<String, QboxError> {
+        let output = run(self.git().args(args), None)?;
+        Ok(String::from_utf8_lossy(&output).trim_end().to_string())
+    }
+
+    fn succeeds(&self, args: &[&str]) -> Result<bool, QboxError> {
+        let status = self.git().args(args).stdout(Stdio::null()).stderr(Stdio::null()).status().map_err(spawn_error)?;
+        Ok(status.success())
+    }
+
+    fn reference(version: &str) -> String {
+        format!("{}{}", REFS_PREFIX, version)
+    }
+
+    /// Last commit of the version, `None` if there is no such version.
+    fn version_commit(&self, version: &str) -> Result<Option<String>, QboxError> {
+        if !self.succeeds(&["rev-parse", "--verify", "--quiet", &format!("{}^{{commit}}", Self::reference(version))])? {
+            return Ok(None);
+        }
+        Ok(Some(self.text(&["rev-parse", &Self::reference(version)])?))
+    }
+
+    fn existing_commit(&self, version: &str) -> Result<String, QboxError> {
+        self.version_commit(version)?.ok_or_else(|| QboxError::VersionMissing(self.location(version)))
+    }
+
+    /// Entries of the commit tree as `ls-tree -z` prints them, with their paths.
+    fn tree_entries(&self, commit: &str, recursive: bool) -> Result<Vec<(String, String)>, QboxError> {
+        let mut command = self.git();
+        command.args(["ls-tree", "-z", "--full-tree"]);
+        if recursive {
+            command.arg("-r");
+        }
+        let output = run(command.arg(commit), None)?;
+        Ok(output.split(|b| *b == 0)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let line = String::from_utf8_lossy(line).to_string();
+                let path = line.split_once('\t').map(|(_, path)| path.to_string()).unwrap_or_default();
+                (line, path)
+            })
+            .collect())
+    }
+
+    /// Commits the tree described by `index_info` on the version reference.
+    /// Nothing is committed if the tree did not change.
+    fn commit(&self, version: &str, parent: Option<&str>, index_info: &[u8], message: &str) -> Result<(), QboxError> {
+        if !self.succeeds(&["check-ref-format", &Self::reference(version)])? {
+            return Err(
+                QboxError::InvalidRefName(version.to_string())
+            );
+        }
+        // A temporary index keeps the index of the repository untouched.
+        let index_dir = tempfile::tempdir_in(self.qbox_path.join(GIT_DIR))?;
+        let index = index_dir.path().join("index");
+        run(self.git().env("GIT_INDEX_FILE", &index).args(["update-index", "-z", "--add", "--index-info"]), Some(index_info))?;
+        let tree = String::from_utf8_lossy(&run(self.git().env("GIT_INDEX_FILE", &index).arg("write-tree"), None)?).trim_end().to_string();
+        if let Some(parent) = parent
+            && self.text(&["rev-parse", &format!("{}^{{tree}}", parent)])? == tree {
+                return Ok(());
+            }
+        let mut command = self.git();
+        command.args(["commit-tree", &tree, "-m", message]);
+        if let Some(parent) = parent {
+            command.args(["-p", parent]);
+        }
+        if !self.succeeds(&["var", "GIT_COMMITTER_IDENT"])? {
+            command.envs(FALLBACK_IDENTITY);
+        }
+        let commit = String::from_utf8_lossy(&run(&mut command, None)?).trim_end().to_string();
+        self.text(&["update-ref", &Self::reference(version), &commit])?;
+        Ok(())
+    }
+
+    /// Index lines for the content of the manifest entries, the objects are added to git.
+    fn content_index_info(&self, manifest: &Manifest) -> Result<Vec<u8>, QboxError> {
+        let entries: Vec<&ManifestEntry> = manifest.files.iter().filter(|entry| entry.link.is_none()).collect();
+        // Paths relative to the object store are plain hex, they are safe to pass line by line.
+        let input: String = entries.iter().map(|entry| format!("{}/{}\n", &entry.hash[..2], &entry.hash[2..])).collect();
+        let blobs = run(self.git().current_dir(self.qbox_path.join(OBJECTS_DIR)).args(["hash-object", "-w", "--stdin-paths"]), Some(input.as_bytes()))?;
+        let mut index_info = Vec::new();
+        for (entry, blob) in entries.iter().zip(String::from_utf8_lossy(&blobs).lines()) {
+            let mode = if entry.mode & 0o111 != 0 { "100755" } else { "100644" };
+            index_info.extend_from_slice(format!("{} {}\t", mode, blob).as_bytes());
+            index_info.extend_from_slice(tree_path(entry).as_os_str().as_encoded_bytes());
+            index_info.push(0);
+        }
+        Ok(index_info)
+    }
+}
+
+impl VersionStore for GitStore {
+    fn list(&self) -> Result<Vec<String>, QboxError> {
+        let references = self.text(&["for-each-ref", "--format=%(refname)", REFS_PREFIX])?;
+        Ok(references.lines().filter_map(|reference| reference.strip_prefix(REFS_PREFIX)).map(str::to_string).collect())
+    }
+
+    /// The files of a version are the files at the top of its last commit.
+    fn metadata(&self, version: &str) -> Result<Option<VersionMetadata>, QboxError> {
+        let Some(commit) = self.version_commit(version)? else {
+            return Ok(None);
+        };
+        let files = self.tree_entries(&commit, false)?.into_iter()
+            .filter(|(line, _)| line.split(' ').nth(1) == Some("blob"))
+            .map(|(_, path)| PathBuf::from(path))
+            .collect();
+        Ok(Some(VersionMetadata { files, legacy: false }))
+    }
+
+    /// A new version is a reference to an empty commit.
+    fn create(&self, version: &str) -> Result<(), QboxError> {
+        if self.version_commit(version)?.is_some() {
+            return Err(
+                QboxError::VersionExists(self.location(version))
+            );
+        }
+        self.commit(version, None, &[], &format!("Create {}", version))
+    }
+
+    fn read_file(&self, version: &str, name: &Path) -> Result<Option<Vec<u8>>, QboxError> {
+        let Some(commit) = self.version_commit(version)? else {
+            return Ok(None);
+        };
+        let object = format!("{}:{}", commit, name.display());
+        if !self.succeeds(&["cat-file", "-e", &object])? {
+            return Ok(None);
+        }
+        Ok(Some(run(self.git().args(["cat-file", "blob", &object]), None)?))
+    }
+
+    /// Commits the file on the version reference. Writing the manifest also commits
+    /// the content of its files, so a record is a single commit.
+    fn write_file(&self, version: &str, name: &Path, content: &[u8]) -> Result<(), QboxError> {
+        let parent = self.existing_commit(version)?;
+        let name_text = name.to_string_lossy();
+        let is_manifest = name == Path::new(MANIFEST_NAME);
+        let mut index_info = Vec::new();
+        for (line, path) in self.tree_entries(&parent, true)? {
+            if path == name_text || (is_manifest && path.starts_with(&format!("{}/", FILES_DIR))) {
+                continue;
+            }
+            index_info.extend_from_slice(line.as_bytes());
+            index_info.push(0);
+        }
+        let blob = run(self.git().args(["hash-object", "-w", "--stdin"]), Some(content))?;
+        index_info.extend_from_slice(format!("100644 {}\t{}", String::from_utf8_lossy(&blob).trim_end(), name_text).as_bytes());
+        index_info.push(0);
+        if is_manifest {
+            let manifest: Manifest = serde_yaml::from_slice(content)?;
+            index_info.extend(self.content_index_info(&manifest)?);
+        }
+        self.commit(version, Some(&parent), &index_info, &format!("Record {}", version))
+    }
+
+    /// Deletes the version reference, its commits stay in the repository until git prunes them.
+    fn delete_version(&self, version: &str) -> Result<(), QboxError> {
+        self.existing_commit(version)?;
+        self.text(&["update-ref", "-d", &Self::reference(version)])?;
+        Ok(())
+    }
+
+    fn location(&self, version: &str) -> PathBuf {
+        self.qbox_path.join(GIT_DIR).join(Self::reference(version))
+    }
+
+    /// Objects missing from the object store are restored from the last commit of the version.
+    fn restore_objects(&self, version: &str, manifest: &Manifest, objects: &ObjectStore) -> Result<(), QboxError> {
+        let missing: Vec<&ManifestEntry> = manifest.files.iter().filter(|entry| entry.link.is_none() && !objects.contains(&entry.hash)).collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let commit = self.existing_commit(version)?;
+        for entry in missing {
+            let object = format!("{}:{}", commit, tree_path(entry).display());
+            let blob = run(self.git().args(["cat-file", "blob", &object]), None)?;
+            if objects.put_reader(&mut blob.as_slice())? != entry.hash {
+                return Err(QboxError::Git { args: format!("cat-file blob {}", object), message: "content does not match the manifest".to_string() });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn tree_path(entry: &ManifestEntry) -> PathBuf {
+    let source = entry.mapping.source.to_string_lossy();
+    let mut path = Path::new(FILES_DIR).join(source.trim_start_matches('/'));
+    if !entry.relative.as_os_str().is_empty() {
+        path.push(&entry.relative);
+    }
+    path
+}
+
+/// Runs the command with the input on stdin and returns its stdout.
+/// The input is written from another thread, so a large output can not block it.
+fn run(command: &mut Command, input: Option<&[u8]>) -> Result<Vec<u8>, QboxError> {
+    let args = command.get_args().map(|arg| arg.to_string_lossy().to_string()).collect::<Vec<_>>().join(" ");
+    let mut child = command
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error)?;
+    let output = thread::scope(|scope| {
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            scope.spawn(move || stdin.write_all(input));
+        }
+        child.wait_with_output()
+    })?;
+    if !output.status.success() {
+        return Err(QboxError::Git { args, message: String::from_utf8_lossy(&output.stderr).trim_end().to_string() });
+    }
+    Ok(output.stdout)
+}
+
+/// A git that can not be started is most likely not installed.
+fn spawn_error(e: io::Error) -> QboxError {
+    if e.kind() == io::ErrorKind::NotFound {
+        QboxError::GitMissing
+    } else {
+        e.into()
+    }
+}
@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+use ignore::{gitignore::{Gitignore, GitignoreBuilder}, overrides::{Override, OverrideBuilder}};
+use crate::{fd, qb::{config::{Config, FileMapping}, error::QboxError}};
+
+/// File with exclude patterns inside a source directory, in gitignore format.
+pub const IGNORE_FILE_NAME: &str = ".qboxignore";
+
+/// Which files of a mapping are taken: exclude patterns in gitignore style
+/// (`*.log`, `**/cache/**`, `!keep.me`) and the include filters of the mapping.
+/// Patterns are matched against paths relative to the mapping root,
+/// so the same filter applies to the source and to the target of the mapping.
+#[derive(Debug)]
+pub struct Filter {
+    excludes: Gitignore,
+    includes: Option<Override>,
+    max_size: Option<u64>,
+    max_depth: Option<usize>,
+}
+
+impl Filter {
+    /// Collects the patterns of the qbox, of the mapping and of the `.qboxignore` files
+    /// under the mapping source. Later patterns take precedence, the same as in gitignore.
+    /// Absolute patterns exclude the path under the mapping source, other absolute patterns are ignored.
+    pub fn new(config: &Config, mapping: &FileMapping, source: &Path) -> Result<Self, QboxError> {
+        let mut builder = GitignoreBuilder::new(source);
+        let mut patterns: Vec<String> = config.excludes.iter().map(|e| e.to_string_lossy().to_string()).collect();
+        for exclude in &mapping.excludes {
+            patterns.push(config.format_path(Path::new(exclude), false)?.to_string_lossy().to_string());
+        }
+        for pattern in patterns {
+            if let Some(pattern) = config_pattern(&pattern, source) {
+                add_pattern(&mut builder, &pattern, Path::new(""))?;
+            }
+        }
+        for ignore_file in ignore_files(source)? {
+            let dir = ignore_file.parent().and_then(|p| p.strip_prefix(source).ok()).unwrap_or(Path::new(""));
+            for line in std::fs::read_to_string(&ignore_file)?.lines() {
+                add_pattern(&mut builder, line, dir)?;
+            }
+        }
+        let includes = if mapping.include.is_empty() {
+            None
+        } else {
+            let mut builder = OverrideBuilder::new(source);
+            for pattern in &mapping.include {
+                builder.add(pattern).map_err(QboxError::Pattern)?;
+            }
+            Some(builder.build().map_err(QboxError::Pattern)?)
+        };
+        Ok(Self {
+            excludes: builder.build().map_err(QboxError::Pattern)?,
+            includes,
+            max_size: mapping.max_size,
+            max_depth: mapping.max_depth,
+        })
+    }
+
+    /// Whether the path relative to the mapping root, or any of its parent directories, is excluded.
+    pub fn is_excluded(&self, relative: &Path, is_dir: bool) -> bool {
+        if relative.as_os_str().is_empty() {
+            return false;
+        }
+        self.excludes.matched_path_or_any_parents(relative, is_dir).is_ignore()
+    }
+
+    /// Whether the entry is left out of the mapping: it is excluded or deeper than the max depth,
+    /// or it is a file that does not match the includes or is larger than the max size.
+    /// The size of a file is `None` if it is unknown.
+    pub fn skips(&self, relative: &Path, is_dir: bool, size: Option<u64>) -> bool {
+        if self.is_excluded(relative, is_dir) {
+            return true;
+        }
+        let depth = relative.components().count();
+        if let Some(max_depth) = self.max_depth {
+            // Files of a directory at the max depth would be deeper than allowed.
+            if depth > max_depth || (is_dir && depth >= max_depth) {
+                return true;
+            }
+        }
+        if is_dir || relative.as_os_str().is_empty() {
+            return false;
+        }
+        if let Some(includes) = &self.includes
+            && !includes.matched(relative, false).is_whitelist() {
+                return true;
+            }
+        self.max_size.zip(size).is_some_and(|(max_size, size)| size > max_size)
+    }
+}
+
+/// Whether the exclude pattern of the config matches any file under the source.
+pub(crate) fn matches_any(pattern: &str, source: &Path) -> Result<bool, QboxError> {
+    let mut builder = GitignoreBuilder::new(source);
+    let Some(pattern) = config_pattern(pattern, source) else {
+        return Ok(false);
+    };
+    add_pattern(&mut builder, &pattern, Path::new(""))?;
+    let matcher = builder.build().map_err(QboxError::Pattern)?;
+    if !source.is_dir() {
+        return Ok(false);
+    }
+    let files = fd::dir::read_all_links(source, None, false)?;
+    Ok(files.iter().any(|file| {
+        let relative = file.strip_prefix(source).unwrap_or(file);
+        matcher.matched_path_or_any_parents(relative, false).is_ignore()
+    }))
+}
+
+pub(crate) fn pattern_matcher(patterns: &[String], source: &Path) -> Result<Gitignore, QboxError> {
+    let mut builder = GitignoreBuilder::new(source);
+    for pattern in patterns {
+        if let Some(pattern) = config_pattern(pattern, source) {
+            add_pattern(&mut builder, &pattern, Path::new(""))?;
+        }
+    }
+    builder.build().map_err(QboxError::Pattern)
+}
+
+/// Config patterns that are absolute paths exclude the path under the mapping source
+/// and are anchored to it, absolute paths outside of the source are dropped.
+fn config_pattern(pattern: &str, source: &Path) -> Option<String> {
+    let (negation, body) = match pattern.strip_prefix('!') {
+        Some(body) => ("!", body),
+        None => ("", pattern),
+    };
+    if !Path::new(body).is_absolute() {
+        return Some(pattern.to_string());
+    }
+    match Path::new(body).strip_prefix(source) {
+        Ok(relative) if !relative.as_os_str().is_empty() => Some(format!("{}/{}", negation, relative.to_string_lossy())),
+        _ => None,
+    }
+}
+
+/// Adds a pattern written in the directory `dir` relative to the mapping source.
+/// Patterns of nested `.qboxignore` files are rewritten to be relative to the source.
+fn add_pattern(builder: &mut GitignoreBuilder, pattern: &str, dir: &Path) -> Result<(), QboxError> {
+    let line = pattern.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+    let (negation, body) = match line.strip_prefix('!') {
+        Some(body) => ("!", body),
+        None => ("", line),
+    };
+    let body = if dir.as_os_str().is_empty() {
+        body.to_string()
+    } else if body.trim_end_matches('/').contains('/') {
+        format!("/{}/{}", dir.to_string_lossy(), body.trim_start_matches('/'))
+    } else {
+        format!("/{}/**/{}", dir.to_string_lossy(), body)
+    };
+    builder.add_line(None, &format!("{}{}", negation, body)).map_err(QboxError::Pattern)?;
+    Ok(())
+}
+
+/// `.qboxignore` files under the source, from the shallowest to the deepest.
+fn ignore_files(source: &Path) -> Result<Vec<PathBuf>, QboxError> {
+    if !source.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files: Vec<PathBuf> = fd::dir::read_all_links(source, None, false)?
+        .into_iter()
+        .filter(|p| p.file_name().is_some_and(|n| n == IGNORE_FILE_NAME))
+        .collect();
+    files.sort_by_key(|p| p.components().count());
+    Ok(files)
+}
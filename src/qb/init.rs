@@ -1,17 +1,14 @@
-use crate::{fd, qb::{qbox}};
-use std::{io, path::PathBuf};
+use crate::qb::{error::QboxError, qbox};
+use std::{fs, path::PathBuf};
 
-fn make_boxes(data_dir: PathBuf) -> io::Result<()>{
+fn make_boxes(data_dir: PathBuf) -> Result<(), QboxError>{
     let boxes_path = qbox::get_boxes_path(data_dir);
     if !boxes_path.exists(){
-        let created = fd::dir::make(boxes_path.to_str().unwrap())?;
-        if !created {
-            return Err(io::Error::other(format!("Error creating directory \"{}\"", boxes_path.to_str().unwrap())));
-        }
+        fs::create_dir_all(&boxes_path).map_err(|source| QboxError::CreateDir { path: boxes_path.clone(), source })?;
     }
     Ok(())
 }
 
-pub fn init(data_dir: PathBuf) -> io::Result<()> {
+pub fn init(data_dir: PathBuf) -> Result<(), QboxError> {
     make_boxes(data_dir)
-}
\ No newline at end of file
+}
@@ -0,0 +1,186 @@
+use std::{collections::HashSet, fs, path::{Path, PathBuf}};
+use crate::{fd, qb::{error::QboxError, manifest::{Manifest, ManifestEntry}, plan::{Operation, Plan}, qbox::Qbox, state::{Applied, Linked, State}, store, template::Renderer, time}};
+
+/// Directory of a version with the working copy of its files the target paths link to.
+pub const LINK_TREE_DIR: &str = "tree";
+
+impl Qbox {
+    /// The tree mirrors the absolute target paths, so every target has its own place.
+    pub(crate) fn link_tree_path(&self, version: &str, target: &Path) -> PathBuf {
+        let relative = target.strip_prefix("/").unwrap_or(target);
+        self.link_tree(version).join(relative)
+    }
+
+    /// Working copy of the version. It lives in the qbox directory whatever the version store is,
+    /// the links need real files to point to.
+    fn link_tree(&self, version: &str) -> PathBuf {
+        self.qbox_path.join(version).join(LINK_TREE_DIR)
+    }
+
+    /// Error if the version is linked: its working copy holds edits that are not in the manifest yet.
+    pub(crate) fn check_not_linked(&self, version: &str) -> Result<(), QboxError> {
+        match State::read(&self.qbox_path)?.linked {
+            Some(linked) if linked.version == version => Err(QboxError::AlreadyLinked(linked.version)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the path is a symlink into a working copy of this qbox.
+    /// Such links are created by `link` and are read as the files they point to.
+    pub(crate) fn is_owned_link(&self, path: &Path) -> bool {
+        fd::dir::is_symlink(path) && fs::read_link(path).is_ok_and(|link| link.starts_with(&self.qbox_path))
+    }
+
+    /// Applies the version as a symlink farm: every target path becomes a link
+    /// to the working copy of the file in the version tree, so edits of the live files
+    /// land in the version. Existing working copies are kept as they are.
+    /// An existing file or foreign link at a target path is a conflict,
+    /// with `force` it is replaced. The created links are remembered for `unlink`.
+    pub fn link(&self, version: &str, force: bool, no_backup: bool) -> Result<(), QboxError> {
+        let plan = self.plan_link(version, force)?;
+        self.execute(&plan, no_backup)?;
+        let manifest = Manifest::read_from(self.version_store.as_ref(), version)?;
+        let mut state = State::read(&self.qbox_path)?;
+        state.linked = Some(Linked {
+            version: version.to_string(),
+            links: self.entry_targets(&manifest)?.into_iter().map(|(_, target)| target).collect(),
+        });
+        state.applied = Some(Applied { version: version.to_string(), applied_at: time::now(), files: Some(manifest.files) });
+        state.write(&self.qbox_path)?;
+        Ok(())
+    }
+
+    pub fn plan_link(&self, version: &str, force: bool) -> Result<Plan, QboxError> {
+        let manifest = self.recorded_manifest(version)?;
+        if let Some(linked) = State::read(&self.qbox_path)?.linked
+            && linked.version != version {
+                return Err(QboxError::AlreadyLinked(linked.version));
+            }
+        let renderer = self.renderer(manifest.files.iter())?;
+        let mut plan = Plan::new();
+        let mut conflicts = Vec::new();
+        let mut made: HashSet<PathBuf> = HashSet::new();
+        for (entry, target) in self.entry_targets(&manifest)? {
+            if entry.encrypted {
+                return Err(QboxError::EncryptedLink(target));
+            }
+            let tree_path = self.link_tree_path(version, &target);
+            if !fd::dir::entry_exists(&tree_path) {
+                plan_make_parent(&mut plan, &mut made, &tree_path);
+                self.plan_tree_file(&mut plan, entry, &tree_path, renderer.as_ref())?;
+            }
+            if fs::read_link(&target).is_ok_and(|link| link == tree_path) {
+                continue;
+            }
+            let exists = fd::dir::entry_exists(&target);
+            if exists && !force {
+                conflicts.push(target);
+                continue;
+            }
+            plan_make_parent(&mut plan, &mut made, &target);
+            // A symlink only replaces a file, a directory has to be removed first.
+            let is_dir = exists && !fd::dir::is_symlink(&target) && target.is_dir();
+            if is_dir {
+                plan.push(Operation::RemoveDir { path: target.clone(), recursive: true });
+            }
+            plan.push(Operation::Symlink { target, link: tree_path, replace: exists && !is_dir });
+        }
+        if !conflicts.is_empty() {
+            return Err(QboxError::LinkConflict(conflicts));
+        }
+        Ok(plan)
+    }
+
+    /// Removes the symlink farm. Every link that still points to the working copy
+    /// is replaced with a regular copy of the file, the edits made through the links
+    /// are written into the version manifest and the working copy is deleted.
+    pub fn unlink(&self, no_backup: bool) -> Result<(), QboxError> {
+        let plan = self.plan_unlink()?;
+        self.execute(&plan, no_backup)?;
+        let mut state = State::read(&self.qbox_path)?;
+        // The edits made through the links are in the version now, they are no drift.
+        if let Some(linked) = state.linked.take()
+            && let Some(applied) = state.applied.as_mut().filter(|applied| applied.version == linked.version) {
+                applied.files = Some(Manifest::read_from(self.version_store.as_ref(), &linked.version)?.files);
+            }
+        state.write(&self.qbox_path)?;
+        Ok(())
+    }
+
+    pub fn plan_unlink(&self) -> Result<Plan, QboxError> {
+        let Some(linked) = State::read(&self.qbox_path)?.linked else {
+            return Err(QboxError::NotLinked(self.qbox_path.clone()));
+        };
+        let mut manifest = Manifest::read_from(self.version_store.as_ref(), &linked.version)?;
+        let mut plan = Plan::new();
+        let mut planned: HashSet<String> = HashSet::new();
+        let mut updated = Vec::new();
+        for (entry, target) in self.entry_targets(&manifest)? {
+            let tree_path = self.link_tree_path(&linked.version, &target);
+            // Links that were replaced or deleted since are no longer owned.
+            if !linked.links.contains(&target)
+                || !fs::read_link(&target).is_ok_and(|link| link == tree_path)
+                || !fd::dir::entry_exists(&tree_path) {
+                continue;
+            }
+            if fd::dir::is_symlink(&tree_path) {
+                let link = fs::read_link(&tree_path)?;
+                plan.push(Operation::Symlink { target, link: link.clone(), replace: true });
+                updated.push(ManifestEntry { link: Some(link.clone()), hash: store::hash_link(&link), ..entry.clone() });
+                continue;
+            }
+            // The rendered output of a template can not be turned back into the template,
+            // the link is replaced with the output and the template stays as recorded.
+            if entry.template {
+                plan.push(Operation::Render { target, content: fs::read_to_string(&tree_path)? });
+                continue;
+            }
+            let hash = store::hash_file(&tree_path)?;
+            if !self.store.contains(&hash) && planned.insert(hash.clone()) {
+                plan.push(Operation::StoreObject { source: tree_path.clone(), hash: hash.clone() });
+            }
+            let copy = ManifestEntry::from_file(&tree_path, &entry.mapping, &tree_path, hash.clone())?;
+            let attributes = copy.attributes(&self.config.preserve);
+            plan.push(Operation::Overwrite { hash, target: target.clone() });
+            if !attributes.is_empty() {
+                plan.push(Operation::SetAttributes { target, attributes });
+            }
+            updated.push(ManifestEntry { path: entry.path.clone(), relative: entry.relative.clone(), ..copy });
+        }
+        if !updated.is_empty() {
+            manifest.extend(updated);
+            manifest.recorded_at = time::now();
+            self.plan_write_manifest(&mut plan, &linked.version, manifest)?;
+        }
+        let tree = self.link_tree(&linked.version);
+        if tree.exists() {
+            plan.push(Operation::RemoveDir { path: tree, recursive: true });
+        }
+        Ok(plan)
+    }
+
+    fn plan_tree_file(&self, plan: &mut Plan, entry: &ManifestEntry, tree_path: &Path, renderer: Option<&Renderer>) -> Result<(), QboxError> {
+        if let Some(link) = &entry.link {
+            plan.push(Operation::Symlink { target: tree_path.to_path_buf(), link: link.clone(), replace: false });
+            return Ok(());
+        }
+        if let Some(renderer) = renderer.filter(|_| entry.template) {
+            let content = self.render_entry(renderer, entry)?;
+            plan.push(Operation::Render { target: tree_path.to_path_buf(), content });
+        } else {
+            plan.push(Operation::Create { hash: entry.hash.clone(), target: tree_path.to_path_buf() });
+        }
+        let attributes = entry.attributes(&self.config.preserve);
+        if !attributes.is_empty() {
+            plan.push(Operation::SetAttributes { target: tree_path.to_path_buf(), attributes });
+        }
+        Ok(())
+    }
+}
+
+fn plan_make_parent(plan: &mut Plan, made: &mut HashSet<PathBuf>, path: &Path) {
+    if let Some(parent) = path.parent()
+        && !parent.exists() && made.insert(parent.to_path_buf()) {
+            plan.push(Operation::MakeDir(parent.to_path_buf()));
+        }
+}
@@ -0,0 +1,171 @@
+use std::{collections::BTreeMap, fmt, fs, io, os::unix::fs::{MetadataExt, PermissionsExt}, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+use crate::qb::{config::{Config, Preserve}, error::QboxError, plan::Attributes, store::hash_link, template, time::unix_time, version_store::VersionStore};
+
+pub const MANIFEST_NAME: &str = "manifest.yaml";
+
+/// Description of a recorded version.
+/// The content of every file is kept in the object store under its hash,
+/// the manifest only keeps where the file came from and its metadata.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Manifest {
+    /// Unix time of the last record.
+    pub recorded_at: u64,
+    /// The config, as written in qbox.yaml, the version was recorded with.
+    pub config: Config,
+    pub files: Vec<ManifestEntry>,
+}
+
+/// Config mapping as written in qbox.yaml, before variables are applied.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Mapping {
+    pub source: PathBuf,
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ManifestEntry {
+    /// Absolute path of the file at the moment of recording.
+    pub path: PathBuf,
+    pub mapping: Mapping,
+    /// Path of the file relative to the mapping source.
+    pub relative: PathBuf,
+    pub size: u64,
+    pub mtime: u64,
+    #[serde(default)]
+    pub atime: u64,
+    pub mode: u32,
+    #[serde(default)]
+    pub uid: u32,
+    #[serde(default)]
+    pub gid: u32,
+    /// Content hash, for a symlink the hash of the path it points to.
+    pub hash: String,
+    /// The path a symlink points to, `None` for regular files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<PathBuf>,
+    /// The file is a template, `apply` writes its rendered output.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub template: bool,
+    /// The object holds the encrypted content, `hash` is the hash of the encrypted content.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+}
+
+impl ManifestEntry {
+    /// The recorded attributes that the config asks to preserve.
+    /// Links get no attributes, changing them would change the file the link points to.
+    pub fn attributes(&self, preserve: &Preserve) -> Attributes {
+        if self.link.is_some() {
+            return Attributes::default();
+        }
+        Attributes {
+            mode: preserve.mode.then_some(self.mode),
+            owner: preserve.owner.then_some((self.uid, self.gid)),
+            times: preserve.times.then_some((self.atime, self.mtime)),
+        }
+    }
+
+    /// Path of the file relative to the mapping target.
+    /// It differs from `relative` for templates, which lose their suffix.
+    pub fn target_relative(&self) -> PathBuf {
+        if self.template {
+            template::rendered_path(&self.relative)
+        } else {
+            self.relative.clone()
+        }
+    }
+
+    pub fn from_file(path: &Path, mapping: &Mapping, mapping_root: &Path, hash: String) -> io::Result<Self> {
+        Self::from_metadata(path, fs::metadata(path)?, mapping, mapping_root, hash, None)
+    }
+
+    /// Reads the metadata of the symlink at `path`, the link itself is not followed.
+    pub fn from_link(path: &Path, mapping: &Mapping, mapping_root: &Path, link: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        Self::from_metadata(path, metadata, mapping, mapping_root, hash_link(link), Some(link.to_path_buf()))
+    }
+
+    fn from_metadata(path: &Path, metadata: fs::Metadata, mapping: &Mapping, mapping_root: &Path, hash: String, link: Option<PathBuf>) -> io::Result<Self> {
+        let relative = path.strip_prefix(mapping_root).unwrap_or(path).to_path_buf();
+        Ok(Self {
+            path: path.to_path_buf(),
+            mapping: mapping.clone(),
+            relative,
+            size: metadata.len(),
+            mtime: unix_time(metadata.modified()?),
+            atime: unix_time(metadata.accessed()?),
+            mode: metadata.permissions().mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            hash,
+            link,
+            template: false,
+            encrypted: false,
+        })
+    }
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the manifest in the directory, a directory without one has an empty manifest.
+    pub fn read(version_path: &Path) -> Result<Self, QboxError> {
+        let manifest_path = version_path.join(MANIFEST_NAME);
+        if !manifest_path.exists() {
+            return Ok(Self::new());
+        }
+        let content = fs::read_to_string(manifest_path)?;
+        Ok(serde_yaml::from_str(&content)?)
+    }
+
+    pub fn write(&self, version_path: &Path) -> Result<(), QboxError> {
+        let content = serde_yaml::to_string(self)?;
+        fs::write(version_path.join(MANIFEST_NAME), content)?;
+        Ok(())
+    }
+
+    /// Reads the manifest of the version from the version store.
+    /// So has a version of an older qbox, commands that read its files refuse it instead.
+    pub fn read_from(versions: &dyn VersionStore, version: &str) -> Result<Self, QboxError> {
+        match versions.read_file(version, Path::new(MANIFEST_NAME))? {
+            Some(content) => Ok(serde_yaml::from_slice(&content)?),
+            None => Ok(Self::new()),
+        }
+    }
+
+    pub fn write_to(&self, versions: &dyn VersionStore, version: &str) -> Result<(), QboxError> {
+        let content = serde_yaml::to_string(self)?;
+        versions.write_file(version, Path::new(MANIFEST_NAME), content.as_bytes())
+    }
+
+    /// Adds the entries, replacing existing entries with the same path.
+    /// The entries are kept sorted by path.
+    pub fn extend(&mut self, entries: Vec<ManifestEntry>) {
+        let mut merged: BTreeMap<PathBuf, ManifestEntry> = self.files.drain(..)
+            .map(|e| (e.path.clone(), e))
+            .collect();
+        for entry in entries {
+            merged.insert(entry.path.clone(), entry);
+        }
+        self.files = merged.into_values().collect();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VerifyProblem {
+    MissingObject(PathBuf),
+    /// The object content does not match the recorded hash or size.
+    Corrupted(PathBuf),
+}
+
+impl fmt::Display for VerifyProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyProblem::MissingObject(path) => write!(f, "missing object: {}", path.display()),
+            VerifyProblem::Corrupted(path) => write!(f, "corrupted object: {}", path.display()),
+        }
+    }
+}
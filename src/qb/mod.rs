@@ -1,12 +1,29 @@
 use std::{env, fs::create_dir, path::{PathBuf}};
 
+pub mod archive;
+pub mod backup;
+pub mod check;
 pub mod init;
 pub mod qbox;
 pub mod error;
 pub mod config;
+pub mod crypt;
+pub mod diff;
+pub mod git;
+pub mod ignore;
+pub mod link;
+pub mod manifest;
+pub mod plan;
+pub mod transaction;
+pub mod state;
+pub mod status;
+pub mod store;
+pub mod template;
+pub mod time;
+pub mod version_store;
 
 const QBOX_CONFIG_NAME: &str = "qbox.yaml";
-const RESERVED_KEYWORDS: [&str; 1] = ["backup"];
+const RESERVED_KEYWORDS: [&str; 3] = ["backup", "backups", "objects"];
 const V_BACKUP_NAME: &str = "backup";
 
 pub fn data_dir() -> PathBuf {
@@ -0,0 +1,181 @@
+use std::{fmt, fs::{self, OpenOptions}, io::Write, os::unix::fs::{symlink, OpenOptionsExt, PermissionsExt}, path::{Path, PathBuf}, time::{Duration, UNIX_EPOCH}};
+use crate::{fd, qb::{error::QboxError, manifest::Manifest, store::ObjectStore, time, version_store::VersionStore}};
+
+/// A single change of the filesystem or of the version store made by a qbox command.
+#[derive(Debug)]
+pub enum Operation {
+    MakeDir(PathBuf),
+    /// Removes a directory. Without `recursive` the directory must be empty.
+    RemoveDir { path: PathBuf, recursive: bool },
+    Create { hash: String, target: PathBuf },
+    Overwrite { hash: String, target: PathBuf },
+    StoreObject { source: PathBuf, hash: String },
+    StoreEncrypted { source: PathBuf, hash: String, content: Vec<u8> },
+    DeleteObject(String),
+    /// Writes the manifest of a backup into its directory.
+    WriteManifest { path: PathBuf, manifest: Box<Manifest> },
+    /// Writes the manifest of a version into the version store.
+    WriteVersionManifest { version: String, manifest: Box<Manifest> },
+    RemoveVersion(String),
+    SetAttributes { target: PathBuf, attributes: Attributes },
+    Render { target: PathBuf, content: String },
+    Decrypt { target: PathBuf, content: Vec<u8> },
+    /// Creates a symlink at `target` pointing to `link`.
+    /// With `replace` the file or link that is already at `target` is deleted first.
+    Symlink { target: PathBuf, link: PathBuf, replace: bool },
+}
+
+/// Recorded file attributes, `None` attributes are left as they are.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Attributes {
+    pub mode: Option<u32>,
+    pub owner: Option<(u32, u32)>,
+    /// Unix access and modification times.
+    pub times: Option<(u64, u64)>,
+}
+
+impl Attributes {
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.owner.is_none() && self.times.is_none()
+    }
+
+    /// Times are set before the mode, so that a read-only mode does not prevent it.
+    pub fn apply(&self, path: &Path) -> Result<(), QboxError> {
+        if let Some((accessed, modified)) = self.times {
+            fd::file::set_times(path, UNIX_EPOCH + Duration::from_secs(accessed), UNIX_EPOCH + Duration::from_secs(modified))?;
+        }
+        if let Some((uid, gid)) = self.owner {
+            fd::file::set_owner(path, uid, gid)?;
+        }
+        if let Some(mode) = self.mode {
+            fd::file::set_mode(path, mode)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(mode) = self.mode {
+            parts.push(format!("mode {:o}", mode & 0o7777));
+        }
+        if let Some((uid, gid)) = self.owner {
+            parts.push(format!("owner {}:{}", uid, gid));
+        }
+        if let Some((_, modified)) = self.times {
+            parts.push(format!("mtime {}", time::format_utc(modified)));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::MakeDir(path) => write!(f, "mkdir     {}", path.display()),
+            Operation::RemoveDir { path, recursive: true } => write!(f, "delete    {} (with content)", path.display()),
+            Operation::RemoveDir { path, recursive: false } => write!(f, "delete    {}", path.display()),
+            Operation::Create { target, .. } => write!(f, "create    {}", target.display()),
+            Operation::Overwrite { target, .. } => write!(f, "overwrite {}", target.display()),
+            Operation::StoreObject { source, hash } => write!(f, "store     {} as object {}", source.display(), hash),
+            Operation::StoreEncrypted { source, hash, .. } => write!(f, "encrypt   {} as object {}", source.display(), hash),
+            Operation::DeleteObject(hash) => write!(f, "delete    object {}", hash),
+            Operation::WriteManifest { path, manifest } =>
+                write!(f, "write     manifest of {} ({} files)", path.display(), manifest.files.len()),
+            Operation::WriteVersionManifest { version, manifest } =>
+                write!(f, "write     manifest of version {} ({} files)", version, manifest.files.len()),
+            Operation::RemoveVersion(version) => write!(f, "delete    version {}", version),
+            Operation::SetAttributes { target, attributes } => write!(f, "attrs     {} ({})", target.display(), attributes),
+            Operation::Render { target, .. } => write!(f, "render    {}", target.display()),
+            Operation::Decrypt { target, .. } => write!(f, "decrypt   {}", target.display()),
+            Operation::Symlink { target, link, replace: false } => write!(f, "link      {} -> {}", target.display(), link.display()),
+            Operation::Symlink { target, link, replace: true } => write!(f, "relink    {} -> {}", target.display(), link.display()),
+        }
+    }
+}
+
+/// An ordered list of operations.
+/// Commands first build a plan, which is then either printed (dry run) or executed.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub operations: Vec<Operation>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, operation: Operation) {
+        self.operations.push(operation);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Performs the operations in order, stops at the first error.
+    pub fn execute(&self, store: &ObjectStore, versions: &dyn VersionStore) -> Result<(), QboxError> {
+        for operation in &self.operations {
+            match operation {
+                Operation::MakeDir(path) => fs::create_dir_all(path)?,
+                Operation::RemoveDir { path, recursive: true } => fs::remove_dir_all(path)?,
+                Operation::RemoveDir { path, recursive: false } => fs::remove_dir(path)?,
+                Operation::Create { hash, target } | Operation::Overwrite { hash, target } => store.copy_to(hash, target)?,
+                Operation::StoreObject { source, hash } => store.insert(source, hash)?,
+                Operation::StoreEncrypted { hash, content, .. } => store.insert_bytes(content, hash)?,
+                Operation::DeleteObject(hash) => store.remove(hash)?,
+                Operation::WriteManifest { path, manifest } => manifest.write(path)?,
+                Operation::WriteVersionManifest { version, manifest } => manifest.write_to(versions, version)?,
+                Operation::RemoveVersion(version) => versions.delete_version(version)?,
+                Operation::SetAttributes { target, attributes } => attributes.apply(target)?,
+                Operation::Render { target, content } => write_content(target, content.as_bytes())?,
+                Operation::Decrypt { target, content } => write_secret(target, content)?,
+                Operation::Symlink { target, link, replace } => {
+                    if *replace && fd::dir::entry_exists(target) {
+                        fs::remove_file(target)?;
+                    }
+                    symlink(link, target)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the content to the target, a symlink at the target is replaced by the file.
+fn write_content(target: &Path, content: &[u8]) -> Result<(), QboxError> {
+    if fd::dir::is_symlink(target) {
+        fs::remove_file(target)?;
+    }
+    fs::write(target, content)?;
+    Ok(())
+}
+
+/// Writes decrypted content readable by the owner only, recorded attributes are set afterwards.
+/// An existing file loses its permissions before the plaintext is written into it.
+fn write_secret(target: &Path, content: &[u8]) -> Result<(), QboxError> {
+    if fd::dir::is_symlink(target) {
+        fs::remove_file(target)?;
+    }
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(target)?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(content)?;
+    Ok(())
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operations.is_empty() {
+            return write!(f, "nothing to do");
+        }
+        for (i, operation) in self.operations.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", operation)?;
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
-use std::{fs, io, path::{Path, PathBuf}};
-use crate::{fd, qb::{config::{read_config, Config}, error::QboxError, QBOX_CONFIG_NAME, RESERVED_KEYWORDS, V_BACKUP_NAME}};
+use std::{cell::OnceCell, fs, io, path::{Path, PathBuf}};
+use std::collections::{BTreeMap, HashSet};
+use serde::Serialize;
+use crate::{fd, qb::{config::{read_config, Config, FileMapping, LinkMode}, crypt::Cipher, diff::{self, Content, DiffSide, FileDiff}, error::QboxError, ignore::Filter, plan::{Operation, Plan}, transaction::Transaction, manifest::{Manifest, ManifestEntry, Mapping, VerifyProblem, MANIFEST_NAME}, store::{self, ObjectStore}, template::{self, Renderer}, state::{Applied, State}, time, version_store::{self, Storage, VersionStore}, QBOX_CONFIG_NAME, RESERVED_KEYWORDS, V_BACKUP_NAME}};
 
 const BOX_DIR: &str = "boxes";
+const BOX_PREFIX: &str = "qbox_";
 /// Creates a complete path to the boxes.
 pub fn get_boxes_path(data_dir: PathBuf) -> PathBuf {
     data_dir.join(BOX_DIR)
@@ -9,58 +12,109 @@ pub fn get_boxes_path(data_dir: PathBuf) -> PathBuf {
 
 /// Creates the full path to qbox.
 /// Uses the passed directory path as the start, formats the qbox directory name.
-pub fn make_qbox_path(name: &str, data_dir: PathBuf) -> io::Result<PathBuf>{
+pub fn make_qbox_path(name: &str, data_dir: PathBuf) -> Result<PathBuf, QboxError>{
+    check_name(name)?;
     let path = get_boxes_path(data_dir);
     if !path.exists() {
         return Err(
-            io::Error::other("boxes directory not exists")
+            QboxError::MissingBoxes(path)
         );
     }
-    let qbox_path = path.join(format!("qbox_{}", name));
+    let qbox_path = path.join(format!("{}{}", BOX_PREFIX, name));
     Ok(qbox_path)
 }
 
-/// Creates a qbox.
+/// Names of all qboxes, without the directory prefix.
+pub fn list(data_dir: PathBuf) -> Result<Vec<String>, QboxError> {
+    let path = get_boxes_path(data_dir);
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&path)? {
+        let entry = entry?;
+        if !entry.path().is_dir() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_string_lossy().strip_prefix(BOX_PREFIX) {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Creates a qbox with the plain directory layout.
 /// Error if such a qbox already exists.
-pub fn make(name: &str, data_dir: PathBuf) -> io::Result<()>{
+pub fn make(name: &str, data_dir: PathBuf) -> Result<(), QboxError>{
+    make_with(name, data_dir, Storage::default())
+}
+
+/// Creates a qbox that keeps its versions in the given storage.
+pub fn make_with(name: &str, data_dir: PathBuf, storage: Storage) -> Result<(), QboxError>{
     let qbox_path = make_qbox_path(name, data_dir)?;
     if !qbox_path.exists() {
-        let is_make = fd::dir::make(&qbox_path.to_string_lossy())?;
-        if !is_make {
-            return Err(
-                io::Error::other("error creating qbox directory")
-            );
+        fs::create_dir_all(&qbox_path).map_err(|source| QboxError::CreateDir { path: qbox_path.clone(), source })?;
+        if let Err(e) = version_store::init(&qbox_path, storage) {
+            fs::remove_dir_all(&qbox_path)?;
+            return Err(e);
         }
         Ok(())
     } else {
         Err(
-            io::Error::other(format!("qbox qbox_{} already exists", name))
+            QboxError::BoxExists(qbox_path)
         )
     }
 }
 
 /// Deleting qbox.
-pub fn delete(name: &str, data_dir: PathBuf, force: bool) -> io::Result<()>{
+pub fn delete(name: &str, data_dir: PathBuf, force: bool) -> Result<(), QboxError>{
     let qbox_path = make_qbox_path(name, data_dir)?;
     if qbox_path.exists(){
-        let is_delete = fd::dir::delete(&qbox_path.to_string_lossy(), force)?;
-        if !is_delete {
-            return Err(
-                io::Error::other("error deleting qbox directory")
-            );
-        }
+        fd::dir::delete(&qbox_path.to_string_lossy(), force)?;
         Ok(())
     } else {
         Err(
-            io::Error::other(format!("qbox qbox_{} not exists",name))
+            QboxError::MissingQbox(qbox_path)
         )
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct CollectedFile {
+    pub(crate) mapping: FileMapping,
+    pub(crate) root: PathBuf,
+    pub(crate) path: PathBuf,
+    /// Target of the symlink if the file is collected as a link.
+    pub(crate) link: Option<PathBuf>,
+    pub(crate) template: bool,
+    pub(crate) encrypt: bool,
+}
+
+/// Where a manifest is kept: in a version of the version store, in a backup directory
+/// or in the state, as the files of the applied version.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ManifestOwner {
+    Version(String),
+    Backup(PathBuf),
+    Applied,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub name: String,
+    pub files: usize,
+    pub size: u64,
+    /// Unix time of the last record, `None` if the version was never recorded.
+    pub recorded_at: Option<u64>,
+}
+
 #[derive(Debug)]
 pub struct Qbox {
-    config: Config,
-    qbox_path: PathBuf,
+    pub(crate) config: Config,
+    /// The config as written in qbox.yaml, before validation applies variables.
+    pub(crate) raw_config: Config,
+    pub(crate) qbox_path: PathBuf,
+    pub(crate) store: ObjectStore,
+    pub(crate) version_store: Box<dyn VersionStore>,
+    pub(crate) cipher: OnceCell<Cipher>,
 }
 
 impl Qbox {
@@ -68,7 +122,7 @@ impl Qbox {
         let qbox_path = make_qbox_path(name, data_dir)?;
         if qbox_path.exists() {
             Ok(
-                Self {config: Config::new(), qbox_path }
+                Self {config: Config::new(), raw_config: Config::new(), store: ObjectStore::new(&qbox_path), version_store: version_store::open(&qbox_path), cipher: OnceCell::new(), qbox_path }
             )
         } else {
             Err(
@@ -77,10 +131,28 @@ impl Qbox {
         }
     }
 
+    /// Replaces the version store, the qbox keeps its versions in it from now on.
+    pub fn with_version_store(mut self, version_store: Box<dyn VersionStore>) -> Self {
+        self.version_store = version_store;
+        self
+    }
+
+    pub fn name(&self) -> String {
+        let dir_name = self.qbox_path.file_name().unwrap_or_default().to_string_lossy();
+        dir_name.strip_prefix(BOX_PREFIX).unwrap_or(&dir_name).to_string()
+    }
+
     pub fn open(&mut self) -> Result<&Self, QboxError>{
+        self.open_profile(None)
+    }
+
+    /// Opens the qbox with the overlays of the current host and of the profile merged onto its config.
+    pub fn open_profile(&mut self, profile: Option<&str>) -> Result<&Self, QboxError>{
         let config_path = self.qbox_path.join(QBOX_CONFIG_NAME);
         if config_path.exists(){
             let mut readed_config = read_config(config_path)?;
+            readed_config.apply_overlays(&template::hostname(), profile)?;
+            self.raw_config = readed_config.clone();
             readed_config.validate()?;
             self.config = readed_config;
             Ok(self)
@@ -92,148 +164,482 @@ impl Qbox {
     }
 
     pub fn new_version(&self, name: &str) -> Result<(), QboxError> {
-        let version_path = self.qbox_path.join(name);
-        if !version_path.exists(){
-            if !fd::dir::make(&version_path.to_string_lossy())? {
+        self.version_store.create(name)
+    }
+
+    /// Manifest of the version, with its objects in the object store.
+    /// Error if there is no such version.
+    pub(crate) fn version_manifest(&self, version: &str) -> Result<Manifest, QboxError> {
+        match self.version_store.metadata(version)? {
+            None => return Err(
+                QboxError::VersionMissing(self.version_store.location(version))
+            ),
+            Some(metadata) if metadata.legacy => return Err(
+                QboxError::LegacyVersion(self.version_store.location(version))
+            ),
+            Some(_) => {}
+        }
+        let manifest = Manifest::read_from(self.version_store.as_ref(), version)?;
+        self.version_store.restore_objects(version, &manifest, &self.store)?;
+        Ok(manifest)
+    }
+
+    /// Like `version_manifest`, but an error if the version was never recorded.
+    /// The backup is never a recorded version.
+    pub(crate) fn recorded_manifest(&self, version: &str) -> Result<Manifest, QboxError> {
+        let content = if version == V_BACKUP_NAME {
+            None
+        } else {
+            self.version_store.read_file(version, Path::new(MANIFEST_NAME))?
+        };
+        let Some(content) = content else {
+            if self.version_store.metadata(version)?.is_some_and(|metadata| metadata.legacy) {
                 return Err(
-                    QboxError::VersionPathError(version_path, "error creating version directory".to_string())
+                    QboxError::LegacyVersion(self.version_store.location(version))
                 );
             }
-        } else {
             return Err(
-                QboxError::VersionPathError(version_path, "version already exists".to_string())
+                QboxError::VersionNotRecorded(self.version_store.location(version))
             );
-        }
-        Ok(())
+        };
+        let manifest: Manifest = serde_yaml::from_slice(&content)?;
+        self.version_store.restore_objects(version, &manifest, &self.store)?;
+        Ok(manifest)
     }
 
     pub fn remove_version(&self, name: &str, force: bool) -> Result<(), QboxError> {
-        let version_path = self.qbox_path.join(name);
-        if version_path.exists(){
-            if !fd::dir::delete(&version_path.to_string_lossy(), force)? {
-                return Err(
-                    QboxError::VersionPathError(version_path, "uerror deleting version directory".to_string())
-                );
-            }
-        } else {
+        self.plan_remove_version(name, force)?.execute(&self.store, self.version_store.as_ref())
+    }
+
+    /// Plans deletion of the version and of the objects only it references.
+    /// Without `force` only a version that was never recorded can be deleted.
+    pub fn plan_remove_version(&self, name: &str, force: bool) -> Result<Plan, QboxError> {
+        self.check_not_linked(name)?;
+        let Some(metadata) = self.version_store.metadata(name)? else {
+            return Err(
+                QboxError::VersionMissing(self.version_store.location(name))
+            );
+        };
+        if !force && !metadata.files.is_empty() {
             return Err(
-                QboxError::VersionPathError(version_path, "version not exists".to_string())
+                QboxError::VersionNotEmpty(self.version_store.location(name))
             );
         }
-        Ok(())
+        let mut plan = Plan::new();
+        let unreferenced = self.unreferenced_objects(&[(ManifestOwner::Version(name.to_string()), None)])?;
+        plan.push(Operation::RemoveVersion(name.to_string()));
+        for hash in unreferenced {
+            plan.push(Operation::DeleteObject(hash));
+        }
+        Ok(plan)
     }
 
-    pub fn record(&self, version: &str, force: bool) -> Result<(), QboxError> {
-        let version_path = self.qbox_path.join(version);
-        if !version_path.exists(){
-            return Err(
-                QboxError::VersionPathError(version_path, "version not exists".to_string())
-            );
+    /// Names of all versions of the qbox, including the backup.
+    pub fn versions(&self) -> Result<Vec<String>, QboxError> {
+        self.version_store.list()
+    }
+
+    pub fn version_infos(&self) -> Result<Vec<VersionInfo>, QboxError> {
+        let mut infos = Vec::new();
+        for name in self.versions()? {
+            let recorded = self.version_store.metadata(&name)?
+                .is_some_and(|metadata| metadata.files.iter().any(|file| file == Path::new(MANIFEST_NAME)));
+            let manifest = Manifest::read_from(self.version_store.as_ref(), &name)?;
+            infos.push(VersionInfo {
+                name,
+                files: manifest.files.len(),
+                size: manifest.files.iter().map(|f| f.size).sum(),
+                recorded_at: recorded.then_some(manifest.recorded_at),
+            });
+        }
+        Ok(infos)
+    }
+
+    /// Manifests of all versions and backups, a version that was never recorded has an empty one.
+    fn manifests(&self) -> Result<Vec<(ManifestOwner, Manifest)>, QboxError> {
+        let mut manifests = Vec::new();
+        for version in self.versions()? {
+            let manifest = Manifest::read_from(self.version_store.as_ref(), &version)?;
+            manifests.push((ManifestOwner::Version(version), manifest));
         }
-        if force{
-            fd::dir::clear(&version_path)?;
+        for backup in self.backups()? {
+            let backup_path = self.backup_path(&backup.id);
+            let manifest = Manifest::read(&backup_path)?;
+            manifests.push((ManifestOwner::Backup(backup_path), manifest));
         }
-        for file in &self.config.files {
-            for source_path in file.keys(){
-                for write_file_path in fd::dir::read_all(source_path, Some(&self.config.excludes_to_str()))? {
-                    fd::file::create_in_dir(&write_file_path, &version_path)?;
-                }
+        if let Some(files) = State::read(&self.qbox_path)?.applied.and_then(|applied| applied.files) {
+            manifests.push((ManifestOwner::Applied, Manifest { files, ..Manifest::new() }));
+        }
+        Ok(manifests)
+    }
+
+    /// Deletes objects that are no longer referenced by any version or backup manifest.
+    pub fn collect_garbage(&self) -> Result<usize, QboxError> {
+        let mut referenced: HashSet<String> = HashSet::new();
+        for (_, manifest) in self.manifests()? {
+            referenced.extend(manifest.files.into_iter().map(|e| e.hash));
+        }
+        self.store.gc(&referenced)
+    }
+
+    /// Objects that become unreferenced once the manifests of the given owners are replaced.
+    /// `None` means that the version or backup is deleted.
+    pub(crate) fn unreferenced_objects(&self, replaced: &[(ManifestOwner, Option<&Manifest>)]) -> Result<Vec<String>, QboxError> {
+        let mut referenced: HashSet<String> = HashSet::new();
+        for (owner, manifest) in self.manifests()? {
+            if !replaced.iter().any(|(replaced_owner, _)| *replaced_owner == owner) {
+                referenced.extend(manifest.files.into_iter().map(|e| e.hash));
             }
         }
-        Ok(())
+        for (_, manifest) in replaced {
+            if let Some(manifest) = manifest {
+                referenced.extend(manifest.files.iter().map(|e| e.hash.clone()));
+            }
+        }
+        self.store.unreferenced(&referenced)
     }
 
-    /// Creates files that are stored in the version in the selected directory.
-    /// Deletes all files from the selected directory and creates items there that are stored in the version.
-    /// IMPORTANT: Only items at the end of the source path will be created. For example:
-    /// If the source path is /home/user/temp, only items stored in the “temp” directory will be created; nothing else will be touched.
-    pub fn apply(&self, version: &str, force: bool) -> Result<(), QboxError> {
-        if version == V_BACKUP_NAME {
-            self.apply_backup()?;
-            return Ok(());
+    /// Reads all files under the mapping root that pass the filter of the mapping.
+    /// Symlinks are collected as links unless the mapping follows them,
+    /// dangling symlinks are always collected as links.
+    /// Links made by `apply --link` are always read as the files they point to.
+    pub(crate) fn collect_files(&self, mapping: &FileMapping, root: PathBuf) -> Result<Vec<CollectedFile>, QboxError> {
+        let mut files = Vec::new();
+        if !fd::dir::entry_exists(&root) {
+            return Ok(files);
         }
-        let version_path = self.qbox_path.join(version);
-        if !version_path.exists(){
-            return Err(
-                QboxError::VersionPathError(version_path, "version not exists".to_string())
-            );
+        let follow_links = mapping.links == LinkMode::Follow;
+        let filter = self.mapping_filter(mapping)?;
+        let secrets = self.secrets(mapping)?;
+        let skip = |path: &Path, is_dir: bool| {
+            let size = if is_dir { None } else { fs::metadata(path).ok().map(|m| m.len()) };
+            filter.skips(path.strip_prefix(&root).unwrap_or(path), is_dir, size)
+        };
+        for path in fd::dir::read_all_filtered(&root, follow_links, &skip)? {
+            let link = if fd::dir::is_symlink(&path) && (!path.exists() || (!follow_links && !self.is_owned_link(&path))) {
+                Some(fs::read_link(&path)?)
+            } else {
+                None
+            };
+            let encrypt = link.is_none() && secrets.is_encrypted(path.strip_prefix(&root).unwrap_or(&path));
+            files.push(CollectedFile { mapping: mapping.clone(), root: root.clone(), path, link, template: false, encrypt });
         }
-        let mut formatted_v_file_paths: Vec<String> = vec![];
-        for v_file_path in fd::dir::read_all(&version_path, None)? {
-            let formatted_v_file_path =
-                v_file_path
-                    .to_string_lossy()
-                    .strip_prefix(&*version_path.to_string_lossy())
-                    .expect("path is not prefixed by version_path").to_string();
-            formatted_v_file_paths.push(formatted_v_file_path);
-        }
-        for file in &self.config.files {
-            for (source_path, target_path) in file {
-                let string_source_path = source_path.to_string_lossy();
-                for formatted_v_file_path in &formatted_v_file_paths {
-                    if formatted_v_file_path.starts_with(&*string_source_path){
-                        let real_files = formatted_v_file_path.trim_start_matches(&*string_source_path).trim_start_matches("/");
-                        let new_file = Path::new(target_path).join(Path::new(real_files));
-                        if let Some(new_file_parent) = new_file.parent(){
-                            if new_file_parent.exists() && force{
-                                println!("{:?}", new_file_parent);
-                                fs::remove_dir_all(new_file_parent)?;
-                            }
-                            fs::create_dir_all(new_file_parent)?;
-                        }
-                        fs::copy(formatted_v_file_path, new_file)?;
+        Ok(files)
+    }
+
+    pub(crate) fn mapping_filter(&self, mapping: &FileMapping) -> Result<Filter, QboxError> {
+        let (source, _) = self.config.resolve_mapping(&mapping.paths())?;
+        Filter::new(&self.config, mapping, &source)
+    }
+
+    /// Plans storing of the files that are not in the object store yet
+    /// and returns manifest entries for all of them.
+    /// Links are kept in the manifest only, they have no object.
+    /// Encrypted files are encrypted while planning. A file whose plaintext did not change
+    /// since `previous` keeps its object, encrypting it again would give a new one.
+    /// The previous object is decrypted to tell, the manifest holds nothing derived from the plaintext.
+    pub(crate) fn plan_store_files(&self, plan: &mut Plan, files: &[CollectedFile], previous: &Manifest) -> Result<Vec<ManifestEntry>, QboxError> {
+        let mut entries = Vec::new();
+        let mut planned: HashSet<String> = HashSet::new();
+        for file in files {
+            if let Some(link) = &file.link {
+                entries.push(ManifestEntry::from_link(&file.path, &file.mapping.paths(), &file.root, link)?);
+                continue;
+            }
+            let hash = if file.encrypt {
+                let plaintext = fs::read(&file.path)?;
+                // An object that cannot be decrypted, for example under a new key, counts as changed.
+                let unchanged = previous.files.iter().find(|entry| entry.path == file.path && entry.encrypted
+                    && entry.size == plaintext.len() as u64 && self.store.contains(&entry.hash)
+                    && self.object_content(entry).is_ok_and(|content| content == plaintext));
+                match unchanged {
+                    Some(entry) => entry.hash.clone(),
+                    None => {
+                        let content = self.cipher()?.encrypt(&plaintext);
+                        let hash = store::hash_bytes(&content);
+                        plan.push(Operation::StoreEncrypted { source: file.path.clone(), hash: hash.clone(), content });
+                        hash
                     }
                 }
-            }
+            } else {
+                let hash = store::hash_file(&file.path)?;
+                if !self.store.contains(&hash) && planned.insert(hash.clone()) {
+                    plan.push(Operation::StoreObject { source: file.path.clone(), hash: hash.clone() });
+                }
+                hash
+            };
+            let mut entry = ManifestEntry::from_file(&file.path, &file.mapping.paths(), &file.root, hash)?;
+            entry.template = file.template;
+            entry.encrypted = file.encrypt;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Plans writing of the manifest into the version,
+    /// followed by deletion of the objects that are no longer referenced.
+    pub(crate) fn plan_write_manifest(&self, plan: &mut Plan, version: &str, manifest: Manifest) -> Result<(), QboxError> {
+        let unreferenced = self.unreferenced_objects(&[(ManifestOwner::Version(version.to_string()), Some(&manifest))])?;
+        plan.push(Operation::WriteVersionManifest { version: version.to_string(), manifest: Box::new(manifest) });
+        for hash in unreferenced {
+            plan.push(Operation::DeleteObject(hash));
         }
         Ok(())
     }
 
-    pub fn make_backup(&self) -> Result<(), QboxError>{
-        let v_backup_path = self.qbox_path.join(V_BACKUP_NAME);
-        if !v_backup_path.exists() {
-            self.new_version(V_BACKUP_NAME)?;
+    /// Plans copying of the objects to their target files and restoring of their attributes.
+    /// Templates are rendered and encrypted files decrypted while planning,
+    /// their output is written instead of the object.
+    /// With `force` the parent directories of the targets are deleted first,
+    /// before anything is copied, so that no copied file is deleted afterwards.
+    pub(crate) fn plan_copy(&self, targets: &[(&ManifestEntry, PathBuf)], force: bool) -> Result<Plan, QboxError> {
+        let renderer = self.renderer(targets.iter().map(|(entry, _)| *entry))?;
+        let mut plan = Plan::new();
+        let mut removed: Vec<&Path> = Vec::new();
+        if force {
+            // The directory holding the file of a single-file mapping is not part of the mapping.
+            for (_, target) in targets.iter().filter(|(entry, _)| !entry.relative.as_os_str().is_empty()) {
+                if let Some(parent) = target.parent()
+                    && parent.exists() && !removed.iter().any(|r| parent.starts_with(r)) {
+                        plan.push(Operation::RemoveDir { path: parent.to_path_buf(), recursive: true });
+                        removed.push(parent);
+                    }
+            }
         }
-        fd::dir::clear(&v_backup_path)?;
-        for file in &self.config.files {
-            for target_dir in file.values() {
-                let target_dir_path = Path::new(target_dir);
-                if !target_dir_path.exists(){
-                    continue;
-                }
-                for target_file_path in fd::dir::read_all(target_dir_path, Some(&self.config.excludes_to_str()))? {
-                    fd::file::create_in_dir(&target_file_path, &v_backup_path)?;
+        let mut made: HashSet<&Path> = HashSet::new();
+        for (entry, target) in targets {
+            let is_removed = removed.iter().any(|r| target.starts_with(r));
+            if let Some(parent) = target.parent()
+                && (is_removed || !parent.exists()) && made.insert(parent) {
+                    plan.push(Operation::MakeDir(parent.to_path_buf()));
                 }
+            if let Some(link) = &entry.link {
+                let replace = !is_removed && fd::dir::entry_exists(target);
+                plan.push(Operation::Symlink { target: target.clone(), link: link.clone(), replace });
+                continue;
+            }
+            if let Some(renderer) = renderer.as_ref().filter(|_| entry.template) {
+                let content = self.render_entry(renderer, entry)?;
+                plan.push(Operation::Render { target: target.clone(), content });
+            } else if entry.encrypted {
+                plan.push(Operation::Decrypt { target: target.clone(), content: self.object_content(entry)? });
+            } else if !is_removed && fd::dir::entry_exists(target) {
+                plan.push(Operation::Overwrite { hash: entry.hash.clone(), target: target.clone() });
+            } else {
+                plan.push(Operation::Create { hash: entry.hash.clone(), target: target.clone() });
+            }
+            let attributes = entry.attributes(&self.config.preserve);
+            if !attributes.is_empty() {
+                plan.push(Operation::SetAttributes { target: target.clone(), attributes });
             }
         }
+        Ok(plan)
+    }
+
+    /// Renderer of templates, `None` if none of the entries is a template.
+    pub(crate) fn renderer<'a>(&self, mut entries: impl Iterator<Item = &'a ManifestEntry>) -> Result<Option<Renderer>, QboxError> {
+        if entries.any(|entry| entry.template) {
+            Ok(Some(Renderer::new(&self.config)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub(crate) fn render_entry(&self, renderer: &Renderer, entry: &ManifestEntry) -> Result<String, QboxError> {
+        let text = String::from_utf8(self.object_content(entry)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        renderer.render(&self.store.object_path(&entry.hash).to_string_lossy(), &text)
+    }
+
+    /// Records the source files into the version.
+    /// The content goes to the object store, the version itself only keeps a manifest
+    /// with the metadata of every file and the config it was recorded with.
+    /// Without `force` the files are added to the already recorded ones.
+    pub fn record(&self, version: &str, force: bool) -> Result<(), QboxError> {
+        self.plan_record(version, force)?.execute(&self.store, self.version_store.as_ref())
+    }
+
+    pub fn plan_record(&self, version: &str, force: bool) -> Result<Plan, QboxError> {
+        if force {
+            self.check_not_linked(version)?;
+        }
+        let recorded = self.version_manifest(version)?;
+        let mut manifest = if force {
+            Manifest::new()
+        } else {
+            recorded.clone()
+        };
+        let mut files = Vec::new();
+        for mapping in self.raw_config.mappings() {
+            let (source_path, _) = self.config.resolve_mapping(&mapping.paths())?;
+            files.extend(self.collect_files(&mapping, source_path)?);
+        }
+        for file in &mut files {
+            let relative = file.path.strip_prefix(&file.root).unwrap_or(&file.path);
+            file.template = file.link.is_none() && template::is_template(&file.mapping, relative);
+        }
+        let mut plan = Plan::new();
+        manifest.extend(self.plan_store_files(&mut plan, &files, &recorded)?);
+        manifest.recorded_at = time::now();
+        manifest.config = self.raw_config.clone();
+        self.plan_write_manifest(&mut plan, version, manifest)?;
+        Ok(plan)
+    }
+
+    /// Creates files that are stored in the version in the selected directory.
+    /// The target of every file is taken from the mapping it was recorded with,
+    /// variables of the mapping are applied for the current environment.
+    /// With `force` the parent directories of the target files are cleared first.
+    /// Every path that is going to change is saved beforehand and restored if the apply fails,
+    /// `no_backup` turns this off. The applied version is remembered for `status`.
+    /// IMPORTANT: Only items at the end of the source path will be created. For example:
+    /// If the source path is /home/user/temp, only items stored in the “temp” directory will be created; nothing else will be touched.
+    pub fn apply(&self, version: &str, force: bool, no_backup: bool) -> Result<(), QboxError> {
+        let plan = self.plan_apply(version, force)?;
+        self.execute(&plan, no_backup)?;
+        if version != V_BACKUP_NAME {
+            let manifest = Manifest::read_from(self.version_store.as_ref(), version)?;
+            let mut state = State::read(&self.qbox_path)?;
+            state.applied = Some(Applied { version: version.to_string(), applied_at: time::now(), files: Some(manifest.files) });
+            state.write(&self.qbox_path)?;
+        }
         Ok(())
     }
-    
-    /// Apply backup.
-    /// The backup directory contains directories that are absolute paths to files.
-    /// The algorithm formats them so that they are perceived as absolute paths and 
-    /// creates files from the backup using these paths.
-    fn apply_backup(&self) -> Result<(), QboxError> {
-        let v_backup_path = self.qbox_path.join(V_BACKUP_NAME);
-        if !v_backup_path.exists() {
-            return Err(
-                QboxError::VersionPathError(v_backup_path, "backup not exists".to_string())
-            );
+
+    /// Executes the plan inside a transaction, so that a failure restores all changed paths.
+    /// With `no_backup` the plan is executed as is.
+    pub(crate) fn execute(&self, plan: &Plan, no_backup: bool) -> Result<(), QboxError> {
+        if no_backup {
+            return plan.execute(&self.store, self.version_store.as_ref());
         }
-        for file_path in fd::dir::read_all(&v_backup_path, None)? {
-            let file_path_str = file_path.to_string_lossy();
-            let backup_file_path = file_path_str.trim_start_matches(&*v_backup_path.to_string_lossy());
-            if let Some(target_dir) = Path::new(backup_file_path).parent()
-                && !target_dir.exists() {
-                    fs::create_dir_all(target_dir)?;
+        Transaction::begin(plan, &self.qbox_path, self.version_store.as_ref())?.execute(plan, &self.store, self.version_store.as_ref())
+    }
+
+    pub fn plan_apply(&self, version: &str, force: bool) -> Result<Plan, QboxError> {
+        if version == V_BACKUP_NAME {
+            return self.plan_restore_latest_backup();
+        }
+        let manifest = self.version_manifest(version)?;
+        let targets = self.entry_targets(&manifest)?;
+        self.plan_copy(&targets, force)
+    }
+
+    /// Pairs every file of the manifest with the path it is applied to.
+    /// Files left out by the filters of the current config are skipped.
+    pub(crate) fn entry_targets<'a>(&self, manifest: &'a Manifest) -> Result<Vec<(&'a ManifestEntry, PathBuf)>, QboxError> {
+        let mappings = self.raw_config.mappings();
+        let mut filters: Vec<(&Mapping, Filter)> = Vec::new();
+        let mut targets = Vec::new();
+        for entry in &manifest.files {
+            let index = match filters.iter().position(|(mapping, _)| **mapping == entry.mapping) {
+                Some(index) => index,
+                None => {
+                    let mapping = mappings.iter()
+                        .find(|m| m.paths() == entry.mapping)
+                        .cloned()
+                        .unwrap_or_else(|| FileMapping::new(entry.mapping.source.clone(), entry.mapping.target.clone()));
+                    filters.push((&entry.mapping, self.mapping_filter(&mapping)?));
+                    filters.len() - 1
                 }
-            fs::copy(&file_path, backup_file_path)?;
+            };
+            if filters[index].1.skips(&entry.relative, false, Some(entry.size)) {
+                continue;
+            }
+            let (_, target_root) = self.config.resolve_mapping(&entry.mapping)?;
+            // The file of a single-file mapping is the target itself.
+            let target = if entry.relative.as_os_str().is_empty() { target_root } else { target_root.join(entry.target_relative()) };
+            targets.push((entry, target));
         }
-        Ok(())
+        Ok(targets)
+    }
+
+    pub(crate) fn version_diff_side(&self, version: &str) -> Result<BTreeMap<PathBuf, DiffSide>, QboxError> {
+        self.manifest_diff_side(&self.version_manifest(version)?)
+    }
+
+    pub(crate) fn manifest_diff_side(&self, manifest: &Manifest) -> Result<BTreeMap<PathBuf, DiffSide>, QboxError> {
+        let renderer = self.renderer(manifest.files.iter())?;
+        let mut side = BTreeMap::new();
+        for (entry, target) in self.entry_targets(manifest)? {
+            let side_file = if let Some(renderer) = renderer.as_ref().filter(|_| entry.template) {
+                let rendered = self.render_entry(renderer, entry)?;
+                DiffSide { hash: store::hash_bytes(rendered.as_bytes()), content: Some(Content::Rendered(rendered)) }
+            } else if entry.encrypted {
+                let decrypted = self.object_content(entry)?;
+                DiffSide { hash: store::hash_bytes(&decrypted), content: Some(Content::Decrypted(decrypted)) }
+            } else {
+                let content = entry.link.is_none().then(|| Content::File(self.store.object_path(&entry.hash)));
+                DiffSide { hash: entry.hash.clone(), content }
+            };
+            side.insert(target, side_file);
+        }
+        Ok(side)
     }
+
+    pub(crate) fn live_diff_side(&self) -> Result<BTreeMap<PathBuf, DiffSide>, QboxError> {
+        let mut side = BTreeMap::new();
+        for mapping in self.raw_config.mappings() {
+            let (_, target_path) = self.config.resolve_mapping(&mapping.paths())?;
+            for file in self.collect_files(&mapping, target_path)? {
+                let side_file = match &file.link {
+                    Some(link) => DiffSide { hash: store::hash_link(link), content: None },
+                    None => DiffSide { hash: store::hash_file(&file.path)?, content: Some(Content::File(file.path.clone())) },
+                };
+                side.insert(file.path, side_file);
+            }
+        }
+        Ok(side)
+    }
+
+    /// Compares the version with the live files.
+    /// The live files are the old side, so the statuses describe what `apply` would change:
+    /// `Added` files exist only in the version, `Removed` files exist only in the target paths.
+    pub fn diff(&self, version: &str, unified: bool) -> Result<Vec<FileDiff>, QboxError> {
+        let version_side = self.version_diff_side(version)?;
+        let live_side = self.live_diff_side()?;
+        diff::compare(&live_side, &version_side, unified)
+    }
+
+    /// Compares two versions, `old` is the version the statuses are relative to.
+    /// Files are matched by the path they are applied to.
+    pub fn diff_versions(&self, old: &str, new: &str, unified: bool) -> Result<Vec<FileDiff>, QboxError> {
+        let old_side = self.version_diff_side(old)?;
+        let new_side = self.version_diff_side(new)?;
+        diff::compare(&old_side, &new_side, unified)
+    }
+
+    /// Checks that every file of the version manifest is present in the object store
+    /// and that its content still matches the recorded hash and size.
+    pub fn verify(&self, version: &str) -> Result<Vec<VerifyProblem>, QboxError> {
+        let mut problems = Vec::new();
+        for entry in self.recorded_manifest(version)?.files {
+            if entry.link.is_some() {
+                continue;
+            }
+            let object_path = self.store.object_path(&entry.hash);
+            if !object_path.exists() {
+                problems.push(VerifyProblem::MissingObject(entry.path));
+            } else if (!entry.encrypted && fs::metadata(&object_path)?.len() != entry.size)
+                || store::hash_file(&object_path)? != entry.hash {
+                problems.push(VerifyProblem::Corrupted(entry.path));
+            }
+        }
+        Ok(problems)
+    }
+}
+
+/// Checks that the name can be used as a single directory name.
+/// Names come from the command line and from imported archives, so a name
+/// must never lead outside of the directory it is joined to.
+pub fn check_name(name: &str) -> Result<(), QboxError> {
+    if name.is_empty() || name.contains(['/', '\0']) || name.contains("..") || name.starts_with('.') {
+        return Err(QboxError::InvalidName(name.to_string()));
+    }
+    Ok(())
 }
 
+/// Checks that the name can be used for a version.
 pub fn check_keywords(name: &str) -> Result<(), QboxError>{
+    check_name(name)?;
     if RESERVED_KEYWORDS.contains(&name){
         return Err(QboxError::ReservedKeyword(name.to_string()));
     }
@@ -0,0 +1,50 @@
+use std::{fs, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+use crate::qb::{error::QboxError, manifest::ManifestEntry};
+
+pub const STATE_NAME: &str = "state.yaml";
+
+/// Persistent state of a qbox, kept next to its config.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct State {
+    #[serde(default)]
+    pub applied: Option<Applied>,
+    /// The symlink farm made by `apply --link`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linked: Option<Linked>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Applied {
+    pub version: String,
+    pub applied_at: u64,
+    /// Files of the version as they were applied, `status` compares against them
+    /// even if the version is recorded again. Their objects are kept until the next apply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<ManifestEntry>>,
+}
+
+/// Links from the target paths to the working copy of a version, owned by the qbox.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Linked {
+    pub version: String,
+    pub links: Vec<PathBuf>,
+}
+
+impl State {
+    /// Reads the state of the qbox, a qbox without a state file has the default state.
+    pub fn read(qbox_path: &Path) -> Result<Self, QboxError> {
+        let state_path = qbox_path.join(STATE_NAME);
+        if !state_path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(state_path)?;
+        Ok(serde_yaml::from_str(&content)?)
+    }
+
+    pub fn write(&self, qbox_path: &Path) -> Result<(), QboxError> {
+        let content = serde_yaml::to_string(self)?;
+        fs::write(qbox_path.join(STATE_NAME), content)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use std::{fmt, path::PathBuf};
+use crate::qb::{diff::{self, DiffStatus}, error::QboxError, manifest::Manifest, qbox::Qbox, state::State};
+
+/// How a live file differs from the applied version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drift {
+    Modified,
+    /// The file is in the version but missing from the target path.
+    Deleted,
+    /// The file is in a mapped directory but not in the version.
+    Untracked,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::Modified => write!(f, "modified"),
+            Drift::Deleted => write!(f, "deleted"),
+            Drift::Untracked => write!(f, "untracked"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Status {
+    pub version: String,
+    pub applied_at: u64,
+    /// Live files that drifted from the version, unchanged files are not listed.
+    pub drifted: Vec<(PathBuf, Drift)>,
+}
+
+impl Qbox {
+    /// Compares the live files with the files of the version as it was applied last,
+    /// recording the version since does not change the status.
+    /// A state written by an older qbox has no files, the version is read instead.
+    pub fn status(&self) -> Result<Status, QboxError> {
+        let Some(applied) = State::read(&self.qbox_path)?.applied else {
+            return Err(QboxError::NotApplied(self.qbox_path.clone()));
+        };
+        let version_side = match applied.files {
+            Some(files) => self.manifest_diff_side(&Manifest { files, ..Manifest::new() })?,
+            None => self.version_diff_side(&applied.version)?,
+        };
+        let live_side = self.live_diff_side()?;
+        let mut drifted = Vec::new();
+        for file_diff in diff::compare(&version_side, &live_side, false)? {
+            let drift = match file_diff.status {
+                DiffStatus::Modified => Drift::Modified,
+                DiffStatus::Removed => Drift::Deleted,
+                DiffStatus::Added => Drift::Untracked,
+                DiffStatus::Unchanged => continue,
+            };
+            drifted.push((file_diff.path, drift));
+        }
+        Ok(Status { version: applied.version, applied_at: applied.applied_at, drifted })
+    }
+}
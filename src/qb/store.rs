@@ -0,0 +1,170 @@
+use std::{collections::HashSet, fs::{self, File}, io::{self, Read, Write}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}};
+use sha2::{Digest, Sha256};
+use crate::{fd, qb::error::QboxError};
+
+pub const OBJECTS_DIR: &str = "objects";
+
+/// Hex encoded sha256 of the file content.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub fn hash_bytes(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// Hash of a symlink, made from the path the link points to.
+pub fn hash_link(link: &Path) -> String {
+    hash_bytes(link.as_os_str().as_bytes())
+}
+
+pub fn copy_hashed(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Content-addressed storage shared by all versions of one qbox.
+/// Every object is a file named after the hash of its content, so identical files
+/// recorded in different versions are stored only once.
+/// Objects are laid out as `objects/<first two hash chars>/<rest of the hash>`.
+#[derive(Debug)]
+pub struct ObjectStore {
+    path: PathBuf,
+}
+
+impl ObjectStore {
+    pub fn new(qbox_path: &Path) -> Self {
+        Self { path: qbox_path.join(OBJECTS_DIR) }
+    }
+
+    pub fn object_path(&self, hash: &str) -> PathBuf {
+        self.path.join(&hash[..2]).join(&hash[2..])
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.object_path(hash).exists()
+    }
+
+    /// If an object with the same content already exists, nothing is written.
+    pub fn put(&self, file_path: &Path) -> Result<String, QboxError> {
+        let hash = hash_file(file_path)?;
+        self.insert(file_path, &hash)?;
+        Ok(hash)
+    }
+
+    /// Stores the file under an already computed hash.
+    pub fn insert(&self, file_path: &Path, hash: &str) -> Result<(), QboxError> {
+        if self.contains(hash) {
+            return Ok(());
+        }
+        let object_path = self.object_path(hash);
+        let object_dir = object_path.parent().expect("object path always has a parent");
+        fs::create_dir_all(object_dir)?;
+        // The object is first written to a temporary file so that an interrupted copy
+        // never leaves a truncated object under a valid hash.
+        // Only the content is copied, objects keep the private permissions of the temporary file.
+        let copy = || -> io::Result<()> {
+            let mut tmp = tempfile::NamedTempFile::new_in(object_dir)?;
+            io::copy(&mut File::open(file_path)?, &mut tmp)?;
+            tmp.persist(&object_path).map_err(|e| e.error)?;
+            Ok(())
+        };
+        copy().map_err(|source| QboxError::CopyFailed { src: file_path.to_path_buf(), dst: object_path.clone(), source })
+    }
+
+    /// Stores the content under an already computed hash.
+    pub fn insert_bytes(&self, content: &[u8], hash: &str) -> Result<(), QboxError> {
+        if self.contains(hash) {
+            return Ok(());
+        }
+        let object_path = self.object_path(hash);
+        let object_dir = object_path.parent().expect("object path always has a parent");
+        fs::create_dir_all(object_dir)?;
+        let mut tmp = tempfile::NamedTempFile::new_in(object_dir)?;
+        tmp.write_all(content)?;
+        tmp.persist(&object_path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// The content is hashed while it is written, so the reader is read only once.
+    pub fn put_reader(&self, reader: &mut impl Read) -> Result<String, QboxError> {
+        fs::create_dir_all(&self.path)?;
+        let mut tmp = tempfile::NamedTempFile::new_in(&self.path)?;
+        let hash = copy_hashed(reader, &mut tmp)?;
+        if !self.contains(&hash) {
+            let object_path = self.object_path(&hash);
+            fs::create_dir_all(object_path.parent().expect("object path always has a parent"))?;
+            tmp.persist(&object_path).map_err(|e| e.error)?;
+        }
+        Ok(hash)
+    }
+
+    /// Deletes the object, together with its directory if it becomes empty.
+    pub fn remove(&self, hash: &str) -> Result<(), QboxError> {
+        let object_path = self.object_path(hash);
+        fs::remove_file(&object_path)?;
+        if let Some(parent) = object_path.parent()
+            && fs::read_dir(parent)?.next().is_none() {
+                fs::remove_dir(parent)?;
+            }
+        Ok(())
+    }
+
+    /// Permissions of an existing target are kept, a new target gets the default ones.
+    /// A symlink at the target is replaced by the file, it is not followed.
+    pub fn copy_to(&self, hash: &str, target: &Path) -> Result<(), QboxError> {
+        let object_path = self.object_path(hash);
+        let copy = || -> io::Result<()> {
+            if fd::dir::is_symlink(target) {
+                fs::remove_file(target)?;
+            }
+            io::copy(&mut File::open(&object_path)?, &mut File::create(target)?)?;
+            Ok(())
+        };
+        copy().map_err(|source| QboxError::CopyFailed { src: object_path.clone(), dst: target.to_path_buf(), source })
+    }
+
+    pub fn list(&self) -> Result<Vec<String>, QboxError> {
+        let mut hashes = Vec::new();
+        if !self.path.exists() {
+            return Ok(hashes);
+        }
+        for object in fd::dir::read_all(&self.path, None)? {
+            let relative = object.strip_prefix(&self.path).expect("object is inside the store");
+            let hash: String = relative.components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect();
+            hashes.push(hash);
+        }
+        Ok(hashes)
+    }
+
+    pub fn unreferenced(&self, referenced: &HashSet<String>) -> Result<Vec<String>, QboxError> {
+        Ok(self.list()?.into_iter().filter(|hash| !referenced.contains(hash)).collect())
+    }
+
+    /// Deletes every object whose hash is not in `referenced`.
+    pub fn gc(&self, referenced: &HashSet<String>) -> Result<usize, QboxError> {
+        let unreferenced = self.unreferenced(referenced)?;
+        for hash in &unreferenced {
+            self.remove(hash)?;
+        }
+        Ok(unreferenced.len())
+    }
+}
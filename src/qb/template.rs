@@ -0,0 +1,60 @@
+use std::{collections::BTreeMap, env, fs, path::{Path, PathBuf}};
+use minijinja::{Environment, Value};
+use crate::qb::{config::{Config, FileMapping}, error::QboxError};
+
+/// Files with this suffix are templates, the suffix is dropped from the target path.
+pub const TEMPLATE_SUFFIX: &str = ".tmpl";
+
+pub fn is_template(mapping: &FileMapping, relative: &Path) -> bool {
+    mapping.template || relative.to_string_lossy().ends_with(TEMPLATE_SUFFIX)
+}
+
+pub fn rendered_path(relative: &Path) -> PathBuf {
+    let name = relative.to_string_lossy();
+    match name.strip_suffix(TEMPLATE_SUFFIX) {
+        Some(stripped) if !stripped.is_empty() && !stripped.ends_with('/') => PathBuf::from(stripped),
+        _ => relative.to_path_buf(),
+    }
+}
+
+/// Renders templates with the facts of the machine they are applied on.
+/// A template sees `vars` of the config, `env` with the environment
+/// and `hostname`, `username`, `os`, `arch` and `home` of the machine.
+pub struct Renderer {
+    context: Value,
+}
+
+impl Renderer {
+    pub fn new(config: &Config) -> Result<Self, QboxError> {
+        let env_vars: BTreeMap<String, String> = env::vars().collect();
+        let context = minijinja::context! {
+            vars => config.expanded_vars()?,
+            env => env_vars,
+            hostname => hostname(),
+            username => env::var("USER").or_else(|_| env::var("LOGNAME")).unwrap_or_default(),
+            os => env::consts::OS,
+            arch => env::consts::ARCH,
+            home => env::var("HOME").unwrap_or_default(),
+        };
+        Ok(Self { context })
+    }
+
+    /// Renders the template text, `name` is used in error messages.
+    pub fn render(&self, name: &str, template: &str) -> Result<String, QboxError> {
+        let mut environment = Environment::new();
+        environment.set_keep_trailing_newline(true);
+        environment.add_template(name, template).map_err(QboxError::Template)?;
+        let rendered = environment.get_template(name)
+            .and_then(|t| t.render(&self.context))
+            .map_err(QboxError::Template)?;
+        Ok(rendered)
+    }
+}
+
+pub fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"].iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .or_else(|| env::var("HOSTNAME").ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,45 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SECONDS_IN_DAY: u64 = 86400;
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn now() -> u64 {
+    unix_time(SystemTime::now())
+}
+
+/// Formats unix time as `YYYY-MM-DD HH:MM:SS` in UTC.
+pub fn format_utc(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = split(secs);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second)
+}
+
+/// Formats unix time as `YYYYMMDD-HHMMSS` in UTC, suitable for file names.
+/// Such names sort in chronological order.
+pub fn format_compact(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = split(secs);
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, hour, minute, second)
+}
+
+fn split(secs: u64) -> (u64, u64, u64, u64, u64, u64) {
+    let (year, month, day) = civil_from_days(secs / SECONDS_IN_DAY);
+    let rest = secs % SECONDS_IN_DAY;
+    (year, month, day, rest / 3600, rest % 3600 / 60, rest % 60)
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) date.
+/// The algorithm is taken from Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
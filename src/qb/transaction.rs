@@ -0,0 +1,198 @@
+use std::{collections::BTreeMap, fs, os::unix::fs::symlink, path::{Path, PathBuf}};
+use tempfile::TempDir;
+use crate::{fd, qb::{error::QboxError, plan::{Operation, Plan}, store::ObjectStore, version_store::VersionStore}};
+
+/// State of a path before the plan was executed.
+#[derive(Debug)]
+enum Saved {
+    /// The path did not exist, rollback deletes it.
+    Absent(PathBuf),
+    File { original: PathBuf, copy: PathBuf },
+    Dir { original: PathBuf, copy: PathBuf },
+    Link { original: PathBuf, link: PathBuf },
+}
+
+#[derive(Debug)]
+struct SavedVersion {
+    version: String,
+    /// Files of the version, `None` if the version did not exist and rollback deletes it.
+    files: Option<BTreeMap<PathBuf, Vec<u8>>>,
+}
+
+/// Snapshot of every path and version a plan is going to change.
+/// If the plan fails, the snapshot is used to return the paths to their original state.
+#[derive(Debug)]
+pub struct Transaction {
+    dir: TempDir,
+    saved: Vec<Saved>,
+    saved_versions: Vec<SavedVersion>,
+}
+
+impl Transaction {
+    /// Saves a copy of every path the plan touches.
+    /// The copies are kept in a temporary directory inside `work_dir`,
+    /// the files of the changed versions are kept in memory.
+    pub fn begin(plan: &Plan, work_dir: &Path, versions: &dyn VersionStore) -> Result<Self, QboxError> {
+        let dir = tempfile::Builder::new().prefix(".transaction").tempdir_in(work_dir)?;
+        let mut transaction = Self { dir, saved: Vec::new(), saved_versions: Vec::new() };
+        for operation in &plan.operations {
+            match operation {
+                Operation::MakeDir(path) => {
+                    // Only the topmost missing directory has to be deleted on rollback.
+                    if let Some(missing) = path.ancestors().take_while(|p| !p.exists()).last() {
+                        transaction.save(missing)?;
+                    }
+                }
+                Operation::RemoveDir { path, .. } => transaction.save(path)?,
+                Operation::Create { target, .. }
+                | Operation::Overwrite { target, .. }
+                | Operation::Render { target, .. }
+                | Operation::Decrypt { target, .. }
+                | Operation::Symlink { target, .. } => transaction.save(target)?,
+                Operation::WriteVersionManifest { version, .. } | Operation::RemoveVersion(version) =>
+                    transaction.save_version(version, versions)?,
+                _ => {}
+            }
+        }
+        Ok(transaction)
+    }
+
+    fn save(&mut self, path: &Path) -> Result<(), QboxError> {
+        // A path inside an already saved directory is restored together with the directory.
+        if self.saved.iter().any(|s| s.path() == path || matches!(s, Saved::Dir { original, .. } if path.starts_with(original))) {
+            return Ok(());
+        }
+        let copy = self.dir.path().join(self.saved.len().to_string());
+        let saved = if !fd::dir::entry_exists(path) {
+            Saved::Absent(path.to_path_buf())
+        } else if fd::dir::is_symlink(path) {
+            Saved::Link { original: path.to_path_buf(), link: fs::read_link(path)? }
+        } else if path.is_dir() {
+            copy_dir(path, &copy)?;
+            Saved::Dir { original: path.to_path_buf(), copy }
+        } else {
+            fs::copy(path, &copy)?;
+            Saved::File { original: path.to_path_buf(), copy }
+        };
+        self.saved.push(saved);
+        Ok(())
+    }
+
+    fn save_version(&mut self, version: &str, versions: &dyn VersionStore) -> Result<(), QboxError> {
+        if self.saved_versions.iter().any(|s| s.version == version) {
+            return Ok(());
+        }
+        let files = match versions.metadata(version)? {
+            Some(metadata) => {
+                let mut files = BTreeMap::new();
+                for name in metadata.files {
+                    if let Some(content) = versions.read_file(version, &name)? {
+                        files.insert(name, content);
+                    }
+                }
+                Some(files)
+            }
+            None => None,
+        };
+        self.saved_versions.push(SavedVersion { version: version.to_string(), files });
+        Ok(())
+    }
+
+    pub fn execute(self, plan: &Plan, store: &ObjectStore, versions: &dyn VersionStore) -> Result<(), QboxError> {
+        let Err(error) = plan.execute(store, versions) else {
+            return Ok(());
+        };
+        match self.rollback(versions) {
+            Ok(restored) => Err(QboxError::RolledBack(Box::new(error), restored)),
+            Err(rollback_error) => {
+                let snapshot = self.dir.keep();
+                Err(QboxError::RollbackFailed(Box::new(error), Box::new(rollback_error), snapshot))
+            }
+        }
+    }
+
+    /// Returns all saved paths to their original state, in reverse order of saving,
+    /// then the saved versions. Returns the restored paths and the locations of the restored versions.
+    fn rollback(&self, versions: &dyn VersionStore) -> Result<Vec<PathBuf>, QboxError> {
+        let mut restored = Vec::new();
+        for saved in self.saved.iter().rev() {
+            match saved {
+                Saved::Absent(path) => {
+                    if !fd::dir::entry_exists(path) {
+                        continue;
+                    }
+                    remove_entry(path)?;
+                }
+                Saved::File { original, copy } => {
+                    remove_entry(original)?;
+                    if let Some(parent) = original.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::copy(copy, original)?;
+                }
+                Saved::Dir { original, copy } => {
+                    remove_entry(original)?;
+                    copy_dir(copy, original)?;
+                }
+                Saved::Link { original, link } => {
+                    remove_entry(original)?;
+                    symlink(link, original)?;
+                }
+            }
+            restored.push(saved.path().to_path_buf());
+        }
+        for saved in self.saved_versions.iter().rev() {
+            let exists = versions.metadata(&saved.version)?.is_some();
+            match &saved.files {
+                None if exists => versions.delete_version(&saved.version)?,
+                None => continue,
+                Some(files) => {
+                    if !exists {
+                        versions.create(&saved.version)?;
+                    }
+                    for (name, content) in files {
+                        versions.write_file(&saved.version, name, content)?;
+                    }
+                }
+            }
+            restored.push(versions.location(&saved.version));
+        }
+        Ok(restored)
+    }
+}
+
+impl Saved {
+    fn path(&self) -> &Path {
+        match self {
+            Saved::Absent(path) => path,
+            Saved::File { original, .. } | Saved::Dir { original, .. } | Saved::Link { original, .. } => original,
+        }
+    }
+}
+
+fn remove_entry(path: &Path) -> Result<(), QboxError> {
+    if !fd::dir::is_symlink(path) && path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else if fd::dir::entry_exists(path) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Symlinks are copied as links.
+fn copy_dir(source: &Path, target: &Path) -> Result<(), QboxError> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let target_path = target.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            symlink(fs::read_link(entry.path())?, &target_path)?;
+        } else if file_type.is_dir() {
+            copy_dir(&entry.path(), &target_path)?;
+        } else {
+            fs::copy(entry.path(), &target_path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,224 @@
+use std::{cell::RefCell, collections::BTreeMap, fmt, fs, path::{Path, PathBuf}};
+use crate::qb::{backup::BACKUPS_DIR, error::QboxError, git::{GitStore, GIT_DIR}, link::LINK_TREE_DIR, manifest::{Manifest, MANIFEST_NAME}, qbox::check_name, store::{ObjectStore, OBJECTS_DIR}};
+
+/// Directories of a qbox that are not versions.
+const RESERVED_DIRS: [&str; 2] = [OBJECTS_DIR, BACKUPS_DIR];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VersionMetadata {
+    /// Names of the files of the version, sorted.
+    pub files: Vec<PathBuf>,
+    /// The version was recorded by an older qbox, which copied the files into it
+    /// instead of writing a manifest.
+    pub legacy: bool,
+}
+
+/// Where the versions of a qbox and their files, like the manifest, are kept.
+/// Commands only reach versions through the store, so the layout can be swapped
+/// without touching them. The content of the recorded files is not part of it,
+/// it stays in the object store.
+/// Backups, the working copy of a linked version, the state and the snapshots of a
+/// transaction are not versions: they belong to this machine and have to be real
+/// files, so they stay in the qbox directory whatever the store is.
+pub trait VersionStore: fmt::Debug {
+    /// Names of all versions, sorted.
+    fn list(&self) -> Result<Vec<String>, QboxError>;
+    /// Metadata of the version, `None` if there is no such version.
+    fn metadata(&self, version: &str) -> Result<Option<VersionMetadata>, QboxError>;
+    /// Creates an empty version. Error if the version exists.
+    fn create(&self, version: &str) -> Result<(), QboxError>;
+    /// Content of a file of the version, `None` if the version has no such file.
+    fn read_file(&self, version: &str, name: &Path) -> Result<Option<Vec<u8>>, QboxError>;
+    /// Writes a file of the version, replacing the file if it exists. Error if the version is missing.
+    fn write_file(&self, version: &str, name: &Path, content: &[u8]) -> Result<(), QboxError>;
+    fn delete_version(&self, version: &str) -> Result<(), QboxError>;
+    /// Where the version is kept, used in messages.
+    fn location(&self, version: &str) -> PathBuf;
+    /// Puts the objects of the manifest that are missing from the object store back,
+    /// for stores that keep the content too. Called before the content is read.
+    fn restore_objects(&self, _version: &str, _manifest: &Manifest, _objects: &ObjectStore) -> Result<(), QboxError> {
+        Ok(())
+    }
+}
+
+/// How `qb make` sets up the versions of a new qbox.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Storage {
+    #[default]
+    Directory,
+    Git,
+}
+
+pub fn init(qbox_path: &Path, storage: Storage) -> Result<(), QboxError> {
+    match storage {
+        Storage::Directory => Ok(()),
+        Storage::Git => GitStore::init(qbox_path),
+    }
+}
+
+/// The version store of the qbox, a qbox that is a git repository keeps its versions in git.
+pub fn open(qbox_path: &Path) -> Box<dyn VersionStore> {
+    if qbox_path.join(GIT_DIR).exists() {
+        Box::new(GitStore::new(qbox_path))
+    } else {
+        Box::new(DirectoryStore::new(qbox_path))
+    }
+}
+
+/// The default layout: a directory per version inside the qbox directory.
+/// Every directory of the qbox is a version, except the object store, the backups
+/// and hidden directories.
+#[derive(Debug)]
+pub struct DirectoryStore {
+    path: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(qbox_path: &Path) -> Self {
+        Self { path: qbox_path.to_path_buf() }
+    }
+
+    /// Directory of the version, the name must not lead outside of the qbox.
+    fn version_path(&self, version: &str) -> Result<PathBuf, QboxError> {
+        check_name(version)?;
+        Ok(self.path.join(version))
+    }
+}
+
+impl VersionStore for DirectoryStore {
+    fn list(&self) -> Result<Vec<String>, QboxError> {
+        let mut versions = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() && !name.starts_with('.') && !RESERVED_DIRS.contains(&name.as_str()) {
+                versions.push(name);
+            }
+        }
+        versions.sort();
+        Ok(versions)
+    }
+
+    /// Only the files directly inside the version directory belong to the version,
+    /// subdirectories like the working copy of a linked version do not.
+    /// Other subdirectories without a manifest are the copied files of an older qbox.
+    fn metadata(&self, version: &str) -> Result<Option<VersionMetadata>, QboxError> {
+        let version_path = self.version_path(version)?;
+        if !version_path.is_dir() {
+            return Ok(None);
+        }
+        let mut files = Vec::new();
+        let mut copied = false;
+        for entry in fs::read_dir(&version_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                files.push(PathBuf::from(entry.file_name()));
+            } else if entry.file_name() != LINK_TREE_DIR {
+                copied = true;
+            }
+        }
+        files.sort();
+        let legacy = copied && !files.iter().any(|file| file == Path::new(MANIFEST_NAME));
+        Ok(Some(VersionMetadata { files, legacy }))
+    }
+
+    fn create(&self, version: &str) -> Result<(), QboxError> {
+        let version_path = self.version_path(version)?;
+        if version_path.exists() {
+            return Err(
+                QboxError::VersionExists(version_path)
+            );
+        }
+        fs::create_dir_all(&version_path).map_err(|source| QboxError::CreateDir { path: version_path, source })?;
+        Ok(())
+    }
+
+    fn read_file(&self, version: &str, name: &Path) -> Result<Option<Vec<u8>>, QboxError> {
+        let path = self.version_path(version)?.join(name);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(path)?))
+    }
+
+    fn write_file(&self, version: &str, name: &Path, content: &[u8]) -> Result<(), QboxError> {
+        let version_path = self.version_path(version)?;
+        if !version_path.is_dir() {
+            return Err(
+                QboxError::VersionMissing(version_path)
+            );
+        }
+        fs::write(version_path.join(name), content)?;
+        Ok(())
+    }
+
+    fn delete_version(&self, version: &str) -> Result<(), QboxError> {
+        fs::remove_dir_all(self.version_path(version)?)?;
+        Ok(())
+    }
+
+    fn location(&self, version: &str) -> PathBuf {
+        self.path.join(version)
+    }
+}
+
+/// Versions kept in memory, nothing is written to disk. Meant for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    versions: RefCell<BTreeMap<String, BTreeMap<PathBuf, Vec<u8>>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl VersionStore for MemoryStore {
+    fn list(&self) -> Result<Vec<String>, QboxError> {
+        Ok(self.versions.borrow().keys().cloned().collect())
+    }
+
+    fn metadata(&self, version: &str) -> Result<Option<VersionMetadata>, QboxError> {
+        Ok(self.versions.borrow().get(version).map(|files| VersionMetadata { files: files.keys().cloned().collect(), legacy: false }))
+    }
+
+    fn create(&self, version: &str) -> Result<(), QboxError> {
+        let mut versions = self.versions.borrow_mut();
+        if versions.contains_key(version) {
+            return Err(
+                QboxError::VersionExists(self.location(version))
+            );
+        }
+        versions.insert(version.to_string(), BTreeMap::new());
+        Ok(())
+    }
+
+    fn read_file(&self, version: &str, name: &Path) -> Result<Option<Vec<u8>>, QboxError> {
+        Ok(self.versions.borrow().get(version).and_then(|files| files.get(name)).cloned())
+    }
+
+    fn write_file(&self, version: &str, name: &Path, content: &[u8]) -> Result<(), QboxError> {
+        let mut versions = self.versions.borrow_mut();
+        let Some(files) = versions.get_mut(version) else {
+            return Err(
+                QboxError::VersionMissing(self.location(version))
+            );
+        };
+        files.insert(name.to_path_buf(), content.to_vec());
+        Ok(())
+    }
+
+    fn delete_version(&self, version: &str) -> Result<(), QboxError> {
+        if self.versions.borrow_mut().remove(version).is_none() {
+            return Err(
+                QboxError::VersionMissing(self.location(version))
+            );
+        }
+        Ok(())
+    }
+
+    fn location(&self, version: &str) -> PathBuf {
+        PathBuf::from(format!("memory:{}", version))
+    }
+}
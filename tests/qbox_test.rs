@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+use std::{collections::HashMap, fs, os::unix::fs::PermissionsExt, path::{Path, PathBuf}, time::{Duration, SystemTime}};
 use qbox::{fd, qb::{self, data_dir}};
 use tempfile::{self, tempdir, TempDir};
 
@@ -27,8 +27,9 @@ fn make_config() -> qb::config::Config{
     map.insert(Path::new("/$HOME/rust_projects/vanilla/qbox/tests/source").to_path_buf(), "/$HOME/rust_projects/vanilla/qbox/tests/target".to_string());
     qb::config::Config {
         make_dir: true,
-        files: vec![map],
-        excludes: vec![Path::new("/$HOME/rust_projects/vanilla/qbox/tests/source/ex").to_path_buf()]
+        files: vec![qb::config::FileEntry::Paths(map)],
+        excludes: vec![Path::new("/$HOME/rust_projects/vanilla/qbox/tests/source/ex").to_path_buf()],
+        ..Default::default()
     }
 }
 
@@ -83,6 +84,127 @@ fn config_validate_test(){
     assert!(result.is_ok(), "expected Ok, but got {:?}", result) 
 }
 
+#[test]
+fn config_variables_test(){
+    let tmp = tempdir().unwrap();
+    fs::create_dir_all(tmp.path().join("source")).unwrap();
+    let home = std::env::var("HOME").unwrap();
+    let cache = std::env::var("XDG_CACHE_HOME").ok().filter(|v| !v.is_empty()).unwrap_or(format!("{}/.cache", home));
+    let mut map: HashMap<PathBuf, String> = HashMap::new();
+    map.insert(PathBuf::from("${base}/source"), "$base/target/${QBOX_UNDEFINED_TEST_VAR:-fallback}/$HOME".to_string());
+    let mut config = qb::config::Config {
+        make_dir: true,
+        files: vec![qb::config::FileEntry::Paths(map)],
+        excludes: vec![PathBuf::from("~/notes"), PathBuf::from("$XDG_CACHE_HOME/qbox"), PathBuf::from("*.log")],
+        vars: [("root".to_string(), tmp.path().display().to_string()), ("base".to_string(), "${root}".to_string())].into(),
+        ..Default::default()
+    };
+    let result = config.validate();
+    assert!(result.is_ok(), "expected Ok, but got {:?}", result);
+    let mapping = &config.mappings()[0];
+    assert_eq!(mapping.source, tmp.path().join("source"));
+    assert_eq!(PathBuf::from(&mapping.target), tmp.path().join(format!("target/fallback/{}", home)));
+    assert_eq!(config.excludes, vec![PathBuf::from(format!("{}/notes", home)), PathBuf::from(format!("{}/qbox", cache)), PathBuf::from("*.log")]);
+
+    let mut config = qb::config::Config {
+        excludes: vec![PathBuf::from("$a/x")],
+        vars: [("a".to_string(), "$b".to_string()), ("b".to_string(), "$a".to_string())].into(),
+        ..Default::default()
+    };
+    assert!(matches!(config.validate(), Err(qb::error::QboxError::VariableCycle(_))));
+    let mut config = qb::config::Config { excludes: vec![PathBuf::from("/$QBOX_UNDEFINED_TEST_VAR/x")], ..Default::default() };
+    assert!(matches!(config.validate(), Err(qb::error::QboxError::ConfigUndefinedVariable(name)) if name == "QBOX_UNDEFINED_TEST_VAR"));
+    let mut config = qb::config::Config { excludes: vec![PathBuf::from("/${HOME/x")], ..Default::default() };
+    assert!(matches!(config.validate(), Err(qb::error::QboxError::InvalidVariableSyntax(text)) if text == "${HOME/x"));
+}
+
+#[test]
+fn config_overlays_test(){
+    let yaml = r#"
+make_dir: false
+files:
+  - /src/shell: /dst/shell
+    /src/git: /dst/git
+excludes: ["*.log"]
+vars:
+  email: home@example.com
+hosts:
+  work:
+    files:
+      - /src/git: /work/git
+    excludes: ["!keep.log"]
+    vars:
+      email: work@example.com
+profiles:
+  laptop:
+    make_dir: true
+    files:
+      - source: /src/editor
+        target: /dst/editor
+"#;
+    let base: qb::config::Config = serde_yaml::from_str(yaml).unwrap();
+    let targets = |config: &qb::config::Config| -> Vec<(String, String)> {
+        config.mappings().iter().map(|m| (m.source.display().to_string(), m.target.clone())).collect()
+    };
+
+    let mut config = base.clone();
+    config.apply_overlays("home", None).unwrap();
+    assert_eq!(config.files, base.files, "no overlay must leave the config as it is");
+
+    let mut config = base.clone();
+    config.apply_overlays("work.example.com", None).unwrap();
+    assert!(!config.make_dir);
+    assert_eq!(targets(&config), vec![
+        ("/src/git".to_string(), "/work/git".to_string()),
+        ("/src/shell".to_string(), "/dst/shell".to_string()),
+    ]);
+    assert_eq!(config.excludes, vec![PathBuf::from("*.log"), PathBuf::from("!keep.log")]);
+    assert_eq!(config.vars["email"], "work@example.com");
+
+    let mut config = base.clone();
+    config.apply_overlays("work", Some("laptop")).unwrap();
+    assert!(config.make_dir, "the profile sets make_dir");
+    assert_eq!(targets(&config), vec![
+        ("/src/editor".to_string(), "/dst/editor".to_string()),
+        ("/src/git".to_string(), "/work/git".to_string()),
+        ("/src/shell".to_string(), "/dst/shell".to_string()),
+    ]);
+
+    let mut config = base.clone();
+    assert!(matches!(config.apply_overlays("home", Some("desktop")), Err(qb::error::QboxError::UnknownProfile(name)) if name == "desktop"));
+}
+
+#[test]
+fn config_include_test(){
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path();
+    fs::create_dir_all(dir.join("shared")).unwrap();
+    fs::write(dir.join("qbox.yaml"), "make_dir: true\ninclude: [shared/common.yaml]\nfiles:\n  - /src/git: /mine/git\nexcludes: [\"!keep.log\"]\n").unwrap();
+    fs::write(dir.join("shared/common.yaml"), "include: [../extra.yaml]\nfiles:\n  - /src/git: /dst/git\n    /src/shell: /dst/shell\nexcludes: [\"*.log\"]\nvars:\n  editor: vim\n").unwrap();
+    fs::write(dir.join("extra.yaml"), "files:\n  - /src/editor: /dst/editor\n").unwrap();
+
+    let config = qb::config::read_config(dir.join("qbox.yaml")).unwrap();
+    assert!(config.make_dir);
+    let targets: Vec<(String, String)> = config.mappings().iter().map(|m| (m.source.display().to_string(), m.target.clone())).collect();
+    assert_eq!(targets, vec![
+        ("/src/editor".to_string(), "/dst/editor".to_string()),
+        ("/src/git".to_string(), "/mine/git".to_string()),
+        ("/src/shell".to_string(), "/dst/shell".to_string()),
+    ]);
+    assert_eq!(config.excludes, vec![PathBuf::from("*.log"), PathBuf::from("!keep.log")]);
+    assert_eq!(config.vars["editor"], "vim");
+
+    fs::write(dir.join("extra.yaml"), "include: [qbox.yaml]\n").unwrap();
+    fs::write(dir.join("shared/common.yaml"), "include: [../extra.yaml]\n").unwrap();
+    let cycle = qb::config::read_config(dir.join("qbox.yaml"));
+    assert!(matches!(&cycle, Err(qb::error::QboxError::IncludeCycle(paths)) if paths.len() == 4), "got {:?}", cycle);
+
+    fs::write(dir.join("extra.yaml"), "files: [unclosed\n").unwrap();
+    let broken = qb::config::read_config(dir.join("qbox.yaml"));
+    assert!(matches!(&broken, Err(qb::error::QboxError::IncludedConfig(path, _)) if path.ends_with("extra.yaml")), "got {:?}", broken);
+    assert!(broken.unwrap_err().to_string().contains("extra.yaml"));
+}
+
 fn open_qbox() -> (TempQbox, qb::qbox::Qbox){
     let base = temp_qbox();
     let qbox = qb::qbox::Qbox::new("Q", base.path.as_path().to_path_buf());
@@ -110,6 +232,52 @@ fn qbox_new_remove_version_test(){
     assert!(!base.path.join("boxes/qbox_Q/v1").exists(), "version directory not removed");
 }
 
+#[test]
+fn qbox_errors_test(){
+    use std::error::Error;
+    use qb::error::QboxError;
+    let (base, qbox) = open_qbox();
+    qbox.new_version("v1").unwrap();
+    let exists = qbox.new_version("v1");
+    assert!(matches!(&exists, Err(QboxError::VersionExists(path)) if path.ends_with("v1")), "got {:?}", exists);
+    let missing = qbox.remove_version("v2", true);
+    assert!(matches!(&missing, Err(QboxError::VersionMissing(path)) if path.ends_with("v2")), "got {:?}", missing);
+    let box_exists = qb::qbox::make("Q", base.path.clone());
+    assert!(matches!(&box_exists, Err(QboxError::BoxExists(_))), "got {:?}", box_exists);
+    assert!(matches!(qb::qbox::check_keywords("backup"), Err(QboxError::ReservedKeyword(_))));
+
+    // Older qboxes copied the files into the version directory, such a version is refused, not read as empty.
+    fs::create_dir_all(base.path.join("boxes/qbox_Q/old/home/user")).unwrap();
+    fs::write(base.path.join("boxes/qbox_Q/old/home/user/a.txt"), "a\n").unwrap();
+    let legacy = qbox.apply("old", false, false);
+    assert!(matches!(&legacy, Err(QboxError::LegacyVersion(path)) if path.ends_with("old")), "got {:?}", legacy);
+    assert!(matches!(qbox.export("old", &base.path.join("old.qbox")), Err(QboxError::LegacyVersion(_))));
+    qbox.remove_version("old", false).unwrap();
+
+    let mut config = make_config();
+    config.files.push(qb::config::FileEntry::Mapping(qb::config::FileMapping::new(PathBuf::from("relative/path"), "/tmp/target".to_string())));
+    let invalid = config.validate();
+    assert!(matches!(&invalid, Err(QboxError::InvalidConfigPath { entry, .. }) if entry == "relative/path"), "got {:?}", invalid);
+
+    let codes = [
+        invalid.unwrap_err().exit_code(),
+        box_exists.unwrap_err().exit_code(),
+        exists.unwrap_err().exit_code(),
+        QboxError::NotLinked(base.path.clone()).exit_code(),
+    ];
+    assert_eq!(codes, [2, 3, 4, 5]);
+
+    let copy = QboxError::CopyFailed {
+        src: base.path.join("a"),
+        dst: base.path.join("b"),
+        source: std::io::Error::from(std::io::ErrorKind::NotFound),
+    };
+    assert!(copy.source().is_some_and(|e| e.is::<std::io::Error>()));
+    let rolled_back = QboxError::RolledBack(Box::new(copy), vec![]);
+    assert_eq!(rolled_back.exit_code(), 6);
+    assert!(rolled_back.source().is_some_and(|e| e.to_string().starts_with("error copying")));
+}
+
 fn record_version() -> (TempQbox, qb::qbox::Qbox){
     let (base, qbox) = open_qbox();
     fs::create_dir(base.path.join("boxes/qbox_Q/v1")).unwrap();
@@ -117,9 +285,11 @@ fn record_version() -> (TempQbox, qb::qbox::Qbox){
     assert!(result_record.is_ok(), "expected Ok, but got {:?}", result_record);
     
     let expected_files = ["f1.txt", "f2.txt"];
-    for file in fd::dir::read_all(&base.path.join("boxes/qbox_Q/v1"), None).unwrap() {
-        if let Some(file_name) = file.file_name() {
-            assert!(expected_files.contains(&file_name.to_str().unwrap()), "source file {} not created", file_name.to_str().unwrap());
+    let manifest = qb::manifest::Manifest::read(&base.path.join("boxes/qbox_Q/v1")).unwrap();
+    assert!(manifest.files.len() == 2, "files not recorded");
+    for entry in manifest.files {
+        if let Some(file_name) = entry.path.file_name() {
+            assert!(expected_files.contains(&file_name.to_str().unwrap()), "source file {} not recorded", file_name.to_str().unwrap());
         }
     }
     (base, qbox)
@@ -130,18 +300,60 @@ fn qbox_record_test(){
     record_version();
 }
 
+#[test]
+fn qbox_object_store_test(){
+    let (base, qbox) = record_version();
+    fs::create_dir(base.path.join("boxes/qbox_Q/v2")).unwrap();
+    qbox.record("v2", true).unwrap();
+
+    let store = qb::store::ObjectStore::new(&base.path.join("boxes/qbox_Q"));
+    let objects = store.list().unwrap();
+    assert_eq!(objects.len(), 2, "identical files must be stored once");
+    for hash in &objects {
+        assert!(store.contains(hash), "object {} not found", hash);
+    }
+
+    qbox.remove_version("v1", true).unwrap();
+    assert_eq!(store.list().unwrap().len(), 2, "objects still referenced by v2 were removed");
+    qbox.remove_version("v2", true).unwrap();
+    assert!(store.list().unwrap().is_empty(), "unreferenced objects were not removed");
+}
+
+#[test]
+fn qbox_manifest_test(){
+    let (base, qbox) = record_version();
+    let manifest = qb::manifest::Manifest::read(&base.path.join("boxes/qbox_Q/v1")).unwrap();
+    assert!(manifest.recorded_at > 0, "record time not set");
+    assert_eq!(manifest.config, qb::config::read_config(base.path.join("boxes/qbox_Q/qbox.yaml")).unwrap());
+    for entry in &manifest.files {
+        assert_eq!(entry.mapping.target, "/$HOME/rust_projects/vanilla/qbox/tests/target");
+        assert!(entry.relative.starts_with("tee"), "unexpected relative path {:?}", entry.relative);
+        assert_eq!(entry.size, fs::metadata(&entry.path).unwrap().len());
+    }
+
+    let result = qbox.verify("v1");
+    assert!(result.is_ok(), "expected Ok, but got {:?}", result);
+    assert!(result.unwrap().is_empty(), "fresh version must be intact");
+
+    let store = qb::store::ObjectStore::new(&base.path.join("boxes/qbox_Q"));
+    fs::write(store.object_path(&manifest.files[0].hash), "broken").unwrap();
+    let problems = qbox.verify("v1").unwrap();
+    assert_eq!(problems, vec![qb::manifest::VerifyProblem::Corrupted(manifest.files[0].path.clone())]);
+}
+
 #[test]
 fn qbox_make_backup_test(){
     let (base, qbox) = open_qbox();
     let result_backup = qbox.make_backup();
     assert!(result_backup.is_ok(), "expected Ok, but got {:?}", result_backup);
-    assert!(base.path.join("boxes/qbox_Q/backup").exists(), "backup dir not found");
+    let backups = qbox.backups().unwrap();
+    assert_eq!(backups.len(), 1, "backup not found");
     
     let mut found = false;
-    let files = fd::dir::read_all(&base.path.join("boxes/qbox_Q/backup"), None).unwrap();
-    assert!(!files.is_empty(), "files not created");
-    for file in files {
-        if let Some(file_name) = file.file_name()
+    let manifest = qb::manifest::Manifest::read(&base.path.join("boxes/qbox_Q/backups").join(&backups[0].id)).unwrap();
+    assert!(!manifest.files.is_empty(), "files not created");
+    for entry in manifest.files {
+        if let Some(file_name) = entry.path.file_name()
             && file_name == "file.txt" {
                 found = true;
                 break;
@@ -154,7 +366,7 @@ fn qbox_make_backup_test(){
 fn qbox_apply_test(){
     fd::dir::clear(Path::new("tests/target/tee")).unwrap();
     let (_base, qbox) = record_version();
-    let result_apply = qbox.apply("v1", false);
+    let result_apply = qbox.apply("v1", false, false);
     assert!(result_apply.is_ok(), "expected Ok, but got {:?}", result_apply);
 
     let expected_file = ["f1.txt", "f2.txt"];
@@ -165,4 +377,914 @@ fn qbox_apply_test(){
             assert!(expected_file.contains(&file_name.to_str().unwrap()), "failed to apply, files not found");
         }
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn qbox_single_file_mapping_test(){
+    let base = temp_boxes();
+    qb::qbox::make("T", base.path.clone()).unwrap();
+    let source = base.path.join("source.conf");
+    let target_dir = base.path.join("target");
+    let target = target_dir.join("dest.conf");
+    fs::create_dir_all(&target_dir).unwrap();
+    fs::write(&source, "one\n").unwrap();
+    fs::write(target_dir.join("keep.txt"), "keep\n").unwrap();
+    fs::write(
+        base.path.join("boxes/qbox_T/qbox.yaml"),
+        format!("make_dir: true\nfiles:\n  - \"{}\": \"{}\"\nexcludes: []\n", source.display(), target.display()),
+    ).unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.clone()).unwrap();
+    qbox.open().unwrap();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+
+    let result = qbox.apply("v1", false, false);
+    assert!(result.is_ok(), "expected Ok, but got {:?}", result);
+    assert_eq!(fs::read_to_string(&target).unwrap(), "one\n");
+    assert!(qbox.diff("v1", false).unwrap().iter().all(|d| !d.is_changed()));
+
+    // Forcing the apply replaces the file only, the directory holding it stays.
+    fs::write(&target, "changed\n").unwrap();
+    let plan = qbox.plan_apply("v1", true).unwrap();
+    assert!(plan.operations.iter().all(|op| !matches!(op, qb::plan::Operation::RemoveDir { .. })), "got {:?}", plan.operations);
+    qbox.apply("v1", true, false).unwrap();
+    assert_eq!(fs::read_to_string(&target).unwrap(), "one\n");
+    assert_eq!(fs::read_to_string(target_dir.join("keep.txt")).unwrap(), "keep\n");
+}
+/// Qbox "T" with its own source and target directories inside the temp directory.
+fn temp_qbox_dirs() -> (TempQbox, qb::qbox::Qbox){
+    let base = temp_boxes();
+    qb::qbox::make("T", base.path.as_path().to_path_buf()).unwrap();
+    let source = base.path.join("source");
+    let target = base.path.join("target");
+    fs::create_dir_all(source.join("conf")).unwrap();
+    fs::create_dir_all(&target).unwrap();
+    fs::write(source.join("a.txt"), "one\ntwo\n").unwrap();
+    fs::write(source.join("conf/b.txt"), "b\n").unwrap();
+    fs::write(
+        base.path.join("boxes/qbox_T/qbox.yaml"),
+        format!("make_dir: true\nfiles:\n  - \"{}\": \"{}\"\nexcludes: []\n", source.display(), target.display()),
+    ).unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.as_path().to_path_buf()).unwrap();
+    let opened_qbox = qbox.open();
+    assert!(opened_qbox.is_ok(), "expected Ok, but got {:?}", opened_qbox);
+    (base, qbox)
+}
+
+#[test]
+fn qbox_diff_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let target = base.path.join("target");
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+    qbox.apply("v1", false, false).unwrap();
+
+    let diffs = qbox.diff("v1", false).unwrap();
+    assert_eq!(diffs.len(), 2);
+    assert!(diffs.iter().all(|d| !d.is_changed()), "applied version must be unchanged: {:?}", diffs);
+
+    fs::write(target.join("a.txt"), "one\nthree\n").unwrap();
+    fs::remove_file(target.join("conf/b.txt")).unwrap();
+    fs::write(target.join("new.txt"), "new\n").unwrap();
+    let diffs = qbox.diff("v1", true).unwrap();
+    let status = |name: &str| diffs.iter().find(|d| d.path == target.join(name)).map(|d| d.status);
+    assert_eq!(status("a.txt"), Some(qb::diff::DiffStatus::Modified));
+    assert_eq!(status("conf/b.txt"), Some(qb::diff::DiffStatus::Added));
+    assert_eq!(status("new.txt"), Some(qb::diff::DiffStatus::Removed));
+
+    let unified = diffs.iter().find(|d| d.path == target.join("a.txt")).unwrap().unified.clone().unwrap();
+    assert!(unified.contains("-three") && unified.contains("+two"), "unexpected unified diff:\n{}", unified);
+}
+
+#[test]
+fn qbox_diff_versions_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let source = base.path.join("source");
+    let target = base.path.join("target");
+    qbox.new_version("laptop").unwrap();
+    qbox.record("laptop", true).unwrap();
+
+    fs::write(source.join("a.txt"), "one\nthree\n").unwrap();
+    fs::remove_file(source.join("conf/b.txt")).unwrap();
+    fs::write(source.join("c.txt"), "c\n").unwrap();
+    qbox.new_version("desktop").unwrap();
+    qbox.record("desktop", true).unwrap();
+
+    let diffs = qbox.diff_versions("laptop", "desktop", true).unwrap();
+    let status = |name: &str| diffs.iter().find(|d| d.path == target.join(name)).map(|d| d.status);
+    assert_eq!(status("a.txt"), Some(qb::diff::DiffStatus::Modified));
+    assert_eq!(status("conf/b.txt"), Some(qb::diff::DiffStatus::Removed));
+    assert_eq!(status("c.txt"), Some(qb::diff::DiffStatus::Added));
+
+    let unified = diffs.iter().find(|d| d.path == target.join("a.txt")).unwrap().unified.clone().unwrap();
+    assert!(unified.contains("-two") && unified.contains("+three"), "unexpected unified diff:\n{}", unified);
+    assert!(qbox.diff_versions("laptop", "laptop", false).unwrap().iter().all(|d| !d.is_changed()));
+}
+
+#[test]
+fn qbox_dry_run_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let target = base.path.join("target");
+    qbox.new_version("v1").unwrap();
+
+    let plan = qbox.plan_record("v1", true).unwrap();
+    assert!(!plan.is_empty(), "record plan must not be empty");
+    assert!(!base.path.join("boxes/qbox_T/v1/manifest.yaml").exists(), "planning must not write the manifest");
+    let qbox_path = base.path.join("boxes/qbox_T");
+    plan.execute(&qb::store::ObjectStore::new(&qbox_path), &qb::version_store::DirectoryStore::new(&qbox_path)).unwrap();
+    assert!(base.path.join("boxes/qbox_T/v1/manifest.yaml").exists(), "manifest not written");
+
+    fs::write(target.join("a.txt"), "old\n").unwrap();
+    let plan = qbox.plan_apply("v1", true).unwrap();
+    let text = plan.to_string();
+    assert!(text.contains(&format!("delete    {} (with content)", target.display())), "unexpected plan:\n{}", text);
+    assert!(text.contains(&format!("create    {}", target.join("conf/b.txt").display())), "unexpected plan:\n{}", text);
+    assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "old\n", "planning must not touch target files");
+
+    let plan = qbox.plan_apply("v1", false).unwrap().to_string();
+    assert!(plan.contains(&format!("overwrite {}", target.join("a.txt").display())), "unexpected plan:\n{}", plan);
+
+    let plan = qbox.plan_remove_version("v1", true).unwrap();
+    assert_eq!(plan.operations.iter().filter(|o| matches!(o, qb::plan::Operation::DeleteObject(_))).count(), 2);
+    assert!(base.path.join("boxes/qbox_T/v1").exists(), "planning must not delete the version");
+}
+
+#[test]
+fn qbox_apply_rollback_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let target = base.path.join("target");
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+
+    // conf/b.txt is a directory in the target, so copying it fails after a.txt was overwritten.
+    fs::write(target.join("a.txt"), "old\n").unwrap();
+    fs::create_dir_all(target.join("conf/b.txt/inner")).unwrap();
+    let result = qbox.apply("v1", false, false);
+    match result {
+        Err(qb::error::QboxError::RolledBack(_, restored)) => {
+            assert!(restored.contains(&target.join("a.txt")), "a.txt not rolled back: {:?}", restored);
+        }
+        other => panic!("expected RolledBack, but got {:?}", other),
+    }
+    assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "old\n");
+    assert!(target.join("conf/b.txt/inner").is_dir(), "directory not restored");
+    assert!(!fs::read_dir(base.path.join("boxes/qbox_T")).unwrap().any(|e| e.unwrap().file_name().to_string_lossy().starts_with(".transaction")), "transaction directory not removed");
+
+    fs::remove_dir_all(target.join("conf/b.txt")).unwrap();
+    let result = qbox.apply("v1", true, false);
+    assert!(result.is_ok(), "expected Ok, but got {:?}", result);
+    assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "one\ntwo\n");
+}
+
+#[test]
+fn qbox_backup_history_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let target = base.path.join("target");
+    let config_path = base.path.join("boxes/qbox_T/qbox.yaml");
+    let config = fs::read_to_string(&config_path).unwrap();
+    fs::write(&config_path, format!("{}backup:\n  keep_last: 2\n", config)).unwrap();
+    let mut qbox_policy = qb::qbox::Qbox::new("T", base.path.as_path().to_path_buf()).unwrap();
+    qbox_policy.open().unwrap();
+
+    fs::write(target.join("a.txt"), "first\n").unwrap();
+    qbox_policy.make_backup().unwrap();
+    fs::write(target.join("a.txt"), "second\n").unwrap();
+    qbox_policy.make_backup().unwrap();
+    let backups = qbox_policy.backups().unwrap();
+    assert_eq!(backups.len(), 2, "second backup must not replace the first one");
+    assert_ne!(backups[0].id, backups[1].id);
+
+    fs::write(target.join("a.txt"), "third\n").unwrap();
+    qbox_policy.make_backup().unwrap();
+    let pruned = qbox_policy.backups().unwrap();
+    assert_eq!(pruned.len(), 2, "retention policy not applied");
+    assert_eq!(pruned[0].id, backups[1].id, "the oldest backup must be deleted");
+
+    qbox.restore_backup(&pruned[0].id, false).unwrap();
+    assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "second\n");
+    qbox.apply("backup", false, false).unwrap();
+    assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "third\n");
+    for id in ["../v1", "..", "a/b"] {
+        let result = qbox.restore_backup(id, false);
+        assert!(matches!(result, Err(qb::error::QboxError::InvalidName(_))), "{:?}: got {:?}", id, result);
+    }
+
+    assert!(qb::qbox::check_keywords("backups").is_err(), "backups must be a reserved keyword");
+    let store = qb::store::ObjectStore::new(&base.path.join("boxes/qbox_T"));
+    assert_eq!(store.list().unwrap().len(), 2, "objects of the deleted backup must be collected");
+}
+
+#[test]
+fn qbox_list_test(){
+    let base = temp_boxes();
+    qb::qbox::make("first", base.path.as_path().to_path_buf()).unwrap();
+    qb::qbox::make("second", base.path.as_path().to_path_buf()).unwrap();
+    let result = qb::qbox::list(base.path.as_path().to_path_buf());
+    assert!(result.is_ok(), "expected Ok, but got {:?}", result);
+    assert_eq!(result.unwrap(), vec!["first", "second"]);
+}
+
+#[test]
+fn qbox_version_infos_test(){
+    let (_base, qbox) = temp_qbox_dirs();
+    qbox.new_version("empty").unwrap();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+    qbox.make_backup().unwrap();
+
+    let infos = qbox.version_infos().unwrap();
+    let names: Vec<&str> = infos.iter().map(|i| i.name.as_str()).collect();
+    assert_eq!(names, vec!["empty", "v1"], "backups and objects must not be listed as versions");
+    assert_eq!(infos[0].recorded_at, None);
+    assert_eq!(infos[1].files, 2);
+    assert_eq!(infos[1].size, "one\ntwo\n".len() as u64 + "b\n".len() as u64);
+    assert!(infos[1].recorded_at.is_some());
+}
+
+#[test]
+fn qbox_status_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let target = base.path.join("target");
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+    assert!(matches!(qbox.status(), Err(qb::error::QboxError::NotApplied(_))), "status without apply must fail");
+
+    qbox.apply("v1", false, false).unwrap();
+    let status = qbox.status().unwrap();
+    assert_eq!(status.version, "v1");
+    assert!(status.drifted.is_empty(), "unexpected drift: {:?}", status.drifted);
+
+    // Recording the version again does not change what was applied.
+    fs::write(base.path.join("source/a.txt"), "recorded later\n").unwrap();
+    qbox.record("v1", true).unwrap();
+    qbox.collect_garbage().unwrap();
+    let status = qbox.status().unwrap();
+    assert!(status.drifted.is_empty(), "unexpected drift: {:?}", status.drifted);
+    assert!(qbox.diff("v1", false).unwrap().iter().any(|d| d.is_changed()));
+
+    fs::write(target.join("a.txt"), "edited\n").unwrap();
+    fs::remove_file(target.join("conf/b.txt")).unwrap();
+    fs::write(target.join("conf/new.txt"), "new\n").unwrap();
+    let status = qbox.status().unwrap();
+    assert_eq!(status.drifted, vec![
+        (target.join("a.txt"), qb::status::Drift::Modified),
+        (target.join("conf/b.txt"), qb::status::Drift::Deleted),
+        (target.join("conf/new.txt"), qb::status::Drift::Untracked),
+    ]);
+}
+
+#[test]
+fn qbox_preserve_attributes_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let source = base.path.join("source");
+    let target = base.path.join("target");
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    fs::set_permissions(source.join("a.txt"), fs::Permissions::from_mode(0o750)).unwrap();
+    fd::file::set_times(&source.join("a.txt"), modified, modified).unwrap();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+
+    let manifest = qb::manifest::Manifest::read(&base.path.join("boxes/qbox_T/v1")).unwrap();
+    let entry = manifest.files.iter().find(|e| e.path == source.join("a.txt")).unwrap();
+    assert_eq!(entry.mode & 0o7777, 0o750);
+    assert_eq!(entry.mtime, 1_600_000_000);
+
+    qbox.apply("v1", false, false).unwrap();
+    let metadata = fs::metadata(target.join("a.txt")).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o750, "mode not restored");
+    assert_eq!(metadata.modified().unwrap(), modified, "mtime not restored");
+
+    let config_path = base.path.join("boxes/qbox_T/qbox.yaml");
+    let config = fs::read_to_string(&config_path).unwrap();
+    fs::write(&config_path, format!("{}preserve:\n  mode: false\n  times: false\n", config)).unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.as_path().to_path_buf()).unwrap();
+    qbox.open().unwrap();
+    fs::remove_file(target.join("a.txt")).unwrap();
+    qbox.apply("v1", false, false).unwrap();
+    let metadata = fs::metadata(target.join("a.txt")).unwrap();
+    assert_ne!(metadata.permissions().mode() & 0o7777, 0o750, "mode must not be restored");
+    assert_ne!(metadata.modified().unwrap(), modified, "mtime must not be restored");
+}
+
+#[test]
+fn qbox_symlink_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let source = base.path.join("source");
+    let target = base.path.join("target");
+    std::os::unix::fs::symlink("a.txt", source.join("link.txt")).unwrap();
+    std::os::unix::fs::symlink(".", source.join("conf/loop")).unwrap();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+
+    let manifest = qb::manifest::Manifest::read(&base.path.join("boxes/qbox_T/v1")).unwrap();
+    let link = manifest.files.iter().find(|e| e.path == source.join("link.txt")).unwrap();
+    assert_eq!(link.link, Some(PathBuf::from("a.txt")));
+    let looped = manifest.files.iter().find(|e| e.path == source.join("conf/loop")).unwrap();
+    assert_eq!(looped.link, Some(PathBuf::from(".")));
+    assert_eq!(manifest.files.len(), 4);
+
+    qbox.apply("v1", false, false).unwrap();
+    assert!(fd::dir::is_symlink(&target.join("link.txt")));
+    assert_eq!(fs::read_link(target.join("link.txt")).unwrap(), PathBuf::from("a.txt"));
+    assert_eq!(fs::read_link(target.join("conf/loop")).unwrap(), PathBuf::from("."));
+    assert!(qbox.diff("v1", false).unwrap().iter().all(|d| !d.is_changed()));
+
+    // With `links: follow` the link is recorded as the file it points to, the cycle is skipped.
+    fs::write(
+        base.path.join("boxes/qbox_T/qbox.yaml"),
+        format!(
+            "make_dir: true\nfiles:\n  - source: \"{}\"\n    target: \"{}\"\n    links: follow\nexcludes: []\n",
+            source.display(), target.display(),
+        ),
+    ).unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.as_path().to_path_buf()).unwrap();
+    qbox.open().unwrap();
+    qbox.new_version("v2").unwrap();
+    qbox.record("v2", true).unwrap();
+    let manifest = qb::manifest::Manifest::read(&base.path.join("boxes/qbox_T/v2")).unwrap();
+    let link = manifest.files.iter().find(|e| e.path == source.join("link.txt")).unwrap();
+    assert_eq!(link.link, None);
+    assert_eq!(manifest.files.len(), 3);
+}
+
+#[test]
+fn qbox_link_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let target = base.path.join("target");
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+
+    fs::write(target.join("a.txt"), "local\n").unwrap();
+    let conflict = qbox.link("v1", false, false);
+    assert!(matches!(&conflict, Err(qb::error::QboxError::LinkConflict(paths)) if paths == &vec![target.join("a.txt")]), "got {:?}", conflict);
+    assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "local\n");
+
+    // A real directory at a target path is replaced by the link too.
+    let _ = fs::remove_file(target.join("conf/b.txt"));
+    fs::create_dir_all(target.join("conf/b.txt/inner")).unwrap();
+    qbox.link("v1", true, false).unwrap();
+    let tree_file = fs::read_link(target.join("a.txt")).unwrap();
+    assert!(tree_file.starts_with(base.path.join("boxes/qbox_T/v1/tree")), "link points to {}", tree_file.display());
+    assert!(fd::dir::is_symlink(&target.join("conf/b.txt")));
+    assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "one\ntwo\n");
+    assert!(qbox.plan_link("v1", false).unwrap().is_empty(), "linking again must change nothing");
+    // The working copy may hold edits the manifest does not have yet.
+    let linked = |result: Result<_, qb::error::QboxError>| matches!(result, Err(qb::error::QboxError::AlreadyLinked(_)));
+    assert!(linked(qbox.record("v1", true)));
+    assert!(linked(qbox.remove_version("v1", true)));
+    assert!(linked(qbox.export("v1", &base.path.join("v1.qbox"))));
+
+    fs::write(target.join("a.txt"), "edited\n").unwrap();
+    assert_eq!(fs::read_to_string(&tree_file).unwrap(), "edited\n");
+    let status = qbox.status().unwrap();
+    assert_eq!(status.drifted, vec![(target.join("a.txt"), qb::status::Drift::Modified)]);
+
+    qbox.unlink(false).unwrap();
+    assert!(!fd::dir::is_symlink(&target.join("a.txt")));
+    assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "edited\n");
+    assert!(!base.path.join("boxes/qbox_T/v1/tree").exists());
+    assert!(qbox.diff("v1", false).unwrap().iter().all(|d| !d.is_changed()), "edits must land in the version");
+    assert!(matches!(qbox.unlink(false), Err(qb::error::QboxError::NotLinked(_))));
+}
+
+#[test]
+fn qbox_exclude_patterns_test(){
+    let (base, _) = temp_qbox_dirs();
+    let source = base.path.join("source");
+    let target = base.path.join("target");
+    for name in ["debug.log", "keep.log", "skip.txt", "cache/data.bin", "conf/cache/x.txt", "conf/skip.txt", "secret/key"] {
+        let path = source.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "x\n").unwrap();
+    }
+    fs::write(source.join("conf/.qboxignore"), "# nested ignore\nskip.txt\n").unwrap();
+    let config_path = base.path.join("boxes/qbox_T/qbox.yaml");
+    fs::write(
+        &config_path,
+        format!(
+            "make_dir: true\nfiles:\n  - source: \"{}\"\n    target: \"{}\"\n    excludes: [\"**/cache/**\", \"!keep.log\"]\nexcludes: [\"*.log\", \"{}\"]\n",
+            source.display(), target.display(), source.join("secret").display(),
+        ),
+    ).unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.as_path().to_path_buf()).unwrap();
+    qbox.open().unwrap();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+
+    let manifest = qb::manifest::Manifest::read(&base.path.join("boxes/qbox_T/v1")).unwrap();
+    let recorded: Vec<PathBuf> = manifest.files.iter().map(|e| e.relative.clone()).collect();
+    let expected: Vec<PathBuf> = ["a.txt", "conf/.qboxignore", "conf/b.txt", "keep.log", "skip.txt"].iter().map(PathBuf::from).collect();
+    assert_eq!(recorded, expected);
+
+    qbox.apply("v1", false, false).unwrap();
+    fs::write(target.join("live.log"), "live\n").unwrap();
+    fs::create_dir_all(target.join("cache")).unwrap();
+    fs::write(target.join("cache/live.bin"), "live\n").unwrap();
+    assert!(qbox.diff("v1", false).unwrap().iter().all(|d| !d.is_changed()), "excluded live files must not be compared");
+
+    // Files recorded before a pattern was added are not applied.
+    let config = fs::read_to_string(&config_path).unwrap();
+    fs::write(&config_path, config.replace("\"*.log\"", "\"*.log\", \"a.txt\"")).unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.as_path().to_path_buf()).unwrap();
+    qbox.open().unwrap();
+    fs::remove_file(target.join("a.txt")).unwrap();
+    qbox.apply("v1", false, false).unwrap();
+    assert!(!target.join("a.txt").exists());
+    assert!(target.join("skip.txt").exists());
+}
+
+#[test]
+fn qbox_include_filters_test(){
+    let (base, _) = temp_qbox_dirs();
+    let source = base.path.join("source");
+    let target = base.path.join("target");
+    fs::write(source.join("app.conf"), "x\n").unwrap();
+    fs::write(source.join("big.toml"), "x".repeat(100)).unwrap();
+    fs::create_dir_all(source.join("conf/deep")).unwrap();
+    fs::write(source.join("conf/c.toml"), "x\n").unwrap();
+    fs::write(source.join("conf/deep/d.conf"), "x\n").unwrap();
+    fs::write(
+        base.path.join("boxes/qbox_T/qbox.yaml"),
+        format!(
+            "make_dir: true\nfiles:\n  - source: \"{}\"\n    target: \"{}\"\n    include: [\"*.conf\", \"*.toml\"]\n    max_size: 10\n    max_depth: 2\nexcludes: []\n",
+            source.display(), target.display(),
+        ),
+    ).unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.as_path().to_path_buf()).unwrap();
+    qbox.open().unwrap();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+
+    let manifest = qb::manifest::Manifest::read(&base.path.join("boxes/qbox_T/v1")).unwrap();
+    let recorded: Vec<PathBuf> = manifest.files.iter().map(|e| e.relative.clone()).collect();
+    assert_eq!(recorded, vec![PathBuf::from("app.conf"), PathBuf::from("conf/c.toml")]);
+
+    qbox.apply("v1", false, false).unwrap();
+    fs::write(target.join("notes.txt"), "live\n").unwrap();
+    assert!(qbox.diff("v1", false).unwrap().iter().all(|d| !d.is_changed()), "filtered live files must not be compared");
+}
+
+#[test]
+fn qbox_template_test(){
+    let (base, _) = temp_qbox_dirs();
+    let source = base.path.join("source");
+    let target = base.path.join("target");
+    fs::write(
+        source.join("greeting.txt.tmpl"),
+        "{% if os == \"linux\" %}on {{ hostname }}{% endif %}\nemail {{ vars.email }}\n{% for n in [1, 2] %}{{ n }}{% endfor %}\n",
+    ).unwrap();
+    let config_path = base.path.join("boxes/qbox_T/qbox.yaml");
+    let config = fs::read_to_string(&config_path).unwrap();
+    fs::write(&config_path, format!("{}vars:\n  email: \"${{USER:-me}}@example.com\"\n", config)).unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.as_path().to_path_buf()).unwrap();
+    qbox.open().unwrap();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+
+    let manifest = qb::manifest::Manifest::read(&base.path.join("boxes/qbox_T/v1")).unwrap();
+    assert!(manifest.files.iter().find(|e| e.relative == Path::new("greeting.txt.tmpl")).unwrap().template);
+    assert!(!manifest.files.iter().find(|e| e.relative == Path::new("a.txt")).unwrap().template);
+
+    qbox.apply("v1", false, false).unwrap();
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname").map(|h| h.trim().to_string()).unwrap_or_default();
+    let user = std::env::var("USER").ok().filter(|u| !u.is_empty()).unwrap_or("me".to_string());
+    let expected = format!("{}\nemail {}@example.com\n12\n", if cfg!(target_os = "linux") { format!("on {}", hostname) } else { String::new() }, user);
+    assert_eq!(fs::read_to_string(target.join("greeting.txt")).unwrap(), expected);
+    assert!(!target.join("greeting.txt.tmpl").exists());
+    assert!(qbox.diff("v1", false).unwrap().iter().all(|d| !d.is_changed()), "diff must compare the rendered output");
+
+    fs::write(target.join("greeting.txt"), "changed\n").unwrap();
+    let status = qbox.status().unwrap();
+    assert_eq!(status.drifted, vec![(target.join("greeting.txt"), qb::status::Drift::Modified)]);
+}
+
+#[test]
+fn qbox_check_config_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let source = base.path.join("source");
+    let target = base.path.join("target");
+    let config_path = base.path.join("boxes/qbox_T/qbox.yaml");
+    fs::write(&config_path, format!(
+        "# relative/path and *.nomatch are located below, not here\nmake_dir: false\nfiles:\n  - \"{source}\": \"{target}\"\n  - \"{source}/conf\": \"{missing}\"\n  - \"relative/path\": \"/x\"\n  - \"$QBOX_UNDEFINED_TEST_VAR/x\": \"/y\"\nexcludes: [\"*.nomatch.bak\", \"*.nomatch\", \"*.txt\"]\n",
+        source = source.display(), target = target.display(), missing = base.path.join("missing").display(),
+    )).unwrap();
+    let problems = qbox.check_config(None).unwrap();
+    let find = |entry: &str, severity: qb::check::Severity| problems.iter().find(|p| p.entry.as_deref() == Some(entry) && p.severity == severity);
+
+    let missing = find(&base.path.join("missing").display().to_string(), qb::check::Severity::Error).expect("missing target");
+    assert_eq!(missing.location.map(|l| l.0), Some(5));
+    assert!(missing.fix.is_some());
+    let relative = find("relative/path", qb::check::Severity::Error).expect("relative source");
+    assert_eq!(relative.location, Some((6, 6)));
+    assert!(find("$QBOX_UNDEFINED_TEST_VAR/x", qb::check::Severity::Error).is_some(), "undefined variable");
+    assert!(find(&source.display().to_string(), qb::check::Severity::Warning).is_some(), "overlapping sources: {:?}", problems);
+    let nomatch = find("*.nomatch", qb::check::Severity::Warning).expect("exclude matching nothing");
+    assert_eq!(nomatch.location, Some((8, 30)), "the longer pattern holding the entry must be skipped");
+    assert!(find("*.txt", qb::check::Severity::Warning).is_none());
+    assert_eq!(problems.iter().filter(|p| p.severity == qb::check::Severity::Error).count(), 3, "{:?}", problems);
+
+    fs::write(&config_path, "make_dir: true\nfiles: [\n").unwrap();
+    let problems = qbox.check_config(None).unwrap();
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].location.is_some());
+}
+
+#[test]
+fn qbox_export_import_test(){
+    let base = temp_boxes();
+    let write_box = |data_dir: &Path, name: &str, root: &Path| {
+        fs::create_dir_all(root.join("source/conf")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        let box_path = data_dir.join(format!("boxes/qbox_{}", name));
+        fs::create_dir_all(&box_path).unwrap();
+        fs::write(
+            box_path.join("qbox.yaml"),
+            format!("make_dir: true\nvars:\n  root: \"{}\"\nfiles:\n  - \"${{root}}/source\": \"${{root}}/target\"\nexcludes: []\n", root.display()),
+        ).unwrap();
+    };
+    let home = base.path.join("home");
+    write_box(&base.path, "T", &home);
+    fs::write(home.join("source/a.txt"), "one\n").unwrap();
+    fs::write(home.join("source/conf/b.txt"), "b\n").unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.clone()).unwrap();
+    qbox.open().unwrap();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+
+    let archive = base.path.join("v1.tar.zst");
+    let result = qbox.export("v1", &archive);
+    assert!(result.is_ok(), "expected Ok, but got {:?}", result);
+    let mut names: Vec<String> = tar::Archive::new(zstd::Decoder::new(fs::File::open(&archive).unwrap()).unwrap())
+        .entries().unwrap()
+        .map(|e| e.unwrap().path().unwrap().display().to_string())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["files/0/a.txt", "files/0/conf/b.txt", "qbox.yaml", "version.yaml"]);
+
+    // Another machine, where the mapping lives under a different root.
+    let other = temp_boxes();
+    let other_home = other.path.join("home");
+    write_box(&other.path, "T", &other_home);
+    let imported = qb::archive::import(&archive, other.path.clone(), None, false);
+    assert_eq!(imported.unwrap(), qb::archive::Imported::Version { qbox: "T".to_string(), version: "v1".to_string() });
+    let manifest = qb::manifest::Manifest::read(&other.path.join("boxes/qbox_T/v1")).unwrap();
+    assert!(manifest.files.iter().all(|e| e.path.starts_with(other_home.join("source"))), "paths not resolved: {:?}", manifest.files);
+    let mut other_qbox = qb::qbox::Qbox::new("T", other.path.clone()).unwrap();
+    other_qbox.open().unwrap();
+    assert!(other_qbox.verify("v1").unwrap().is_empty());
+    other_qbox.apply("v1", false, false).unwrap();
+    assert_eq!(fs::read_to_string(other_home.join("target/conf/b.txt")).unwrap(), "b\n");
+
+    let again = qb::archive::import(&archive, other.path.clone(), None, false);
+    assert!(matches!(again, Err(qb::error::QboxError::VersionExists(_))), "got {:?}", again);
+
+    // A missing qbox is created with the config of the archive.
+    let created = qb::archive::import(&archive, other.path.clone(), Some("copy"), false);
+    assert!(created.is_ok(), "expected Ok, but got {:?}", created);
+    assert_eq!(
+        fs::read_to_string(other.path.join("boxes/qbox_copy/qbox.yaml")).unwrap(),
+        fs::read_to_string(base.path.join("boxes/qbox_T/qbox.yaml")).unwrap(),
+    );
+
+    // Rewrites the archive, `change` gets the path and the content of every entry.
+    let rewrite = |name: &str, change: &dyn Fn(&Path, &mut Vec<u8>)| {
+        let changed = base.path.join(name);
+        let mut builder = tar::Builder::new(zstd::Encoder::new(fs::File::create(&changed).unwrap(), 0).unwrap());
+        let mut source = tar::Archive::new(zstd::Decoder::new(fs::File::open(&archive).unwrap()).unwrap());
+        for entry in source.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut header = entry.header().clone();
+            let mut content = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut content).unwrap();
+            change(&entry.path().unwrap(), &mut content);
+            header.set_size(content.len() as u64);
+            header.set_cksum();
+            builder.append(&header, content.as_slice()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        changed
+    };
+    let set_version = |path: &Path, content: &mut Vec<u8>, version: &str| {
+        if path == Path::new("version.yaml") {
+            *content = String::from_utf8(content.clone()).unwrap().replace("version: v1", &format!("version: {}", version)).into_bytes();
+        }
+    };
+    let hostile = rewrite("hostile.tar.zst", &|path, content| set_version(path, content, "../../x"));
+    let result = qb::archive::import(&hostile, other.path.clone(), None, false);
+    assert!(matches!(result, Err(qb::error::QboxError::InvalidName(_))), "got {:?}", result);
+    assert!(!other.path.join("boxes/x").exists() && !other.path.join("x").exists(), "manifest written outside of the qbox");
+
+    // A failed import into an existing qbox leaves none of its objects behind.
+    let store = qb::store::ObjectStore::new(&other.path.join("boxes/qbox_T"));
+    let objects = store.list().unwrap();
+    let damaged = rewrite("damaged-v2.tar.zst", &|path, content| {
+        set_version(path, content, "v2");
+        if path.starts_with("files") {
+            content.extend_from_slice(b"changed\n");
+        }
+    });
+    let result = qb::archive::import(&damaged, other.path.clone(), None, false);
+    assert!(matches!(result, Err(qb::error::QboxError::InvalidArchive { .. })), "got {:?}", result);
+    assert_eq!(store.list().unwrap(), objects, "objects of the failed import were left behind");
+}
+
+#[test]
+fn qbox_export_import_box_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+    qbox.new_version("v2").unwrap();
+    qbox.make_backup().unwrap();
+    qbox.apply("v1", false, false).unwrap();
+
+    let archive = base.path.join("T.tar.zst");
+    let result = qbox.export_box(&archive);
+    assert!(result.is_ok(), "expected Ok, but got {:?}", result);
+
+    let other = temp_boxes();
+    let imported = qb::archive::import(&archive, other.path.clone(), None, false);
+    assert_eq!(imported.unwrap(), qb::archive::Imported::Qbox("T".to_string()));
+    let mut copy = qb::qbox::Qbox::new("T", other.path.clone()).unwrap();
+    copy.open().unwrap();
+    assert_eq!(copy.versions().unwrap(), ["v1", "v2"]);
+    assert!(copy.verify("v1").unwrap().is_empty());
+    assert_eq!(copy.backups().unwrap().len(), qbox.backups().unwrap().len());
+    assert_eq!(copy.status().unwrap().version, "v1");
+
+    let conflict = qb::archive::import(&archive, other.path.clone(), None, false);
+    assert!(matches!(conflict, Err(qb::error::QboxError::BoxExists(_))), "got {:?}", conflict);
+    let renamed = qb::archive::import(&archive, other.path.clone(), Some("U"), false);
+    assert_eq!(renamed.unwrap(), qb::archive::Imported::Qbox("U".to_string()));
+    copy.remove_version("v2", true).unwrap();
+    let replaced = qb::archive::import(&archive, other.path.clone(), None, true);
+    assert!(replaced.is_ok(), "expected Ok, but got {:?}", replaced);
+    assert!(other.path.join("boxes/qbox_T/v2").is_dir(), "qbox not replaced");
+
+    // An archive whose file content no longer matches the listed hash is rejected.
+    let damaged = base.path.join("damaged.tar.zst");
+    let mut builder = tar::Builder::new(zstd::Encoder::new(fs::File::create(&damaged).unwrap(), 0).unwrap());
+    let mut source = tar::Archive::new(zstd::Decoder::new(fs::File::open(&archive).unwrap()).unwrap());
+    for entry in source.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut header = entry.header().clone();
+        let mut content = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut content).unwrap();
+        if entry.path().unwrap().ends_with("qbox.yaml") {
+            content.extend_from_slice(b"# changed\n");
+            header.set_size(content.len() as u64);
+            header.set_cksum();
+        }
+        builder.append(&header, content.as_slice()).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap();
+    let result = qb::archive::import(&damaged, other.path.clone(), Some("D"), false);
+    assert!(matches!(result, Err(qb::error::QboxError::InvalidArchive { .. })), "got {:?}", result);
+    assert!(!other.path.join("boxes/qbox_D").exists(), "damaged qbox imported");
+}
+
+#[test]
+fn qbox_encrypt_test(){
+    let base = temp_boxes();
+    qb::qbox::make("T", base.path.clone()).unwrap();
+    let source = base.path.join("source");
+    let target = base.path.join("target");
+    fs::create_dir_all(source.join("gh")).unwrap();
+    fs::create_dir_all(&target).unwrap();
+    fs::write(source.join("a.txt"), "plain\n").unwrap();
+    fs::write(source.join(".netrc"), "machine example.com password secret-token\n").unwrap();
+    fs::write(source.join("gh/hosts.yml"), "oauth_token: gh-token\n").unwrap();
+    let key_file = base.path.join("key");
+    fs::write(&key_file, "correct horse battery staple").unwrap();
+    fs::write(
+        base.path.join("boxes/qbox_T/qbox.yaml"),
+        format!(
+            "make_dir: true\nfiles:\n  - \"{}\": \"{}\"\nexcludes: []\nencrypt:\n  - \".netrc\"\n  - \"**/hosts.yml\"\nkey_file: \"{}\"\npreserve:\n  mode: false\n",
+            source.display(), target.display(), key_file.display(),
+        ),
+    ).unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.clone()).unwrap();
+    qbox.open().unwrap();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+
+    let manifest = qb::manifest::Manifest::read(&base.path.join("boxes/qbox_T/v1")).unwrap();
+    for entry in &manifest.files {
+        let object = fs::read(base.path.join("boxes/qbox_T/objects").join(&entry.hash[..2]).join(&entry.hash[2..])).unwrap();
+        let encrypted = !entry.path.ends_with("a.txt");
+        assert_eq!(entry.encrypted, encrypted, "{}", entry.path.display());
+        assert!(!String::from_utf8_lossy(&object).contains("token"), "{} stored in plaintext", entry.path.display());
+    }
+    assert!(qbox.verify("v1").unwrap().is_empty());
+
+    // Unchanged files keep their objects, only the changed file is encrypted anew.
+    let hashes = || -> Vec<String> {
+        qb::manifest::Manifest::read(&base.path.join("boxes/qbox_T/v1")).unwrap().files.into_iter().map(|entry| entry.hash).collect()
+    };
+    let first = hashes();
+    qbox.record("v1", true).unwrap();
+    assert_eq!(hashes(), first);
+    fs::write(source.join(".netrc"), "machine example.com password other-token\n").unwrap();
+    qbox.record("v1", true).unwrap();
+    let changed: Vec<bool> = hashes().iter().zip(&first).map(|(now, before)| now != before).collect();
+    let paths: Vec<String> = manifest.files.iter().map(|entry| entry.relative.to_string_lossy().to_string()).collect();
+    assert_eq!(paths.iter().zip(changed).filter(|(_, changed)| *changed).map(|(path, _)| path.as_str()).collect::<Vec<_>>(), [".netrc"]);
+    fs::write(source.join(".netrc"), "machine example.com password secret-token\n").unwrap();
+    qbox.record("v1", true).unwrap();
+    assert!(qbox.verify("v1").unwrap().is_empty());
+
+    qbox.apply("v1", false, false).unwrap();
+    assert_eq!(fs::read_to_string(target.join(".netrc")).unwrap(), "machine example.com password secret-token\n");
+    assert_eq!(fs::read_to_string(target.join("gh/hosts.yml")).unwrap(), "oauth_token: gh-token\n");
+    // Decrypted files are readable by the owner only, also without preserved modes.
+    for path in [".netrc", "gh/hosts.yml"] {
+        assert_eq!(fs::metadata(target.join(path)).unwrap().permissions().mode() & 0o777, 0o600, "{}", path);
+    }
+    assert!(!fs::read_to_string(base.path.join("boxes/qbox_T/v1/manifest.yaml")).unwrap().contains("plain"), "plaintext hash in the manifest");
+    assert!(qbox.diff("v1", false).unwrap().iter().all(|d| !d.is_changed()));
+
+    fs::write(target.join(".netrc"), "machine example.com password new-token\n").unwrap();
+    let diffs = qbox.diff("v1", true).unwrap();
+    let changed: Vec<_> = diffs.iter().filter(|d| d.is_changed()).collect();
+    assert_eq!(changed.len(), 1);
+    assert!(changed[0].unified.as_deref().is_some_and(|u| u.contains("+machine example.com password secret-token")), "got {:?}", changed[0].unified);
+
+    let linked = qbox.link("v1", true, false);
+    assert!(matches!(linked, Err(qb::error::QboxError::EncryptedLink(_))), "got {:?}", linked);
+
+    fs::write(&key_file, "wrong key").unwrap();
+    let mut wrong = qb::qbox::Qbox::new("T", base.path.clone()).unwrap();
+    wrong.open().unwrap();
+    let result = wrong.apply("v1", false, false);
+    assert!(matches!(result, Err(qb::error::QboxError::Decrypt(_))), "got {:?}", result);
+}
+
+#[test]
+fn qbox_git_version_store_test(){
+    let git = |git_dir: &Path, args: &[&str]| {
+        let output = std::process::Command::new("git").arg("--git-dir").arg(git_dir).args(args).output().unwrap();
+        assert!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+    let make_box = |data_dir: &Path, root: &Path| {
+        fs::create_dir_all(root.join("source")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        qb::qbox::make_with("T", data_dir.to_path_buf(), qb::version_store::Storage::Git).unwrap();
+        fs::write(
+            data_dir.join("boxes/qbox_T/qbox.yaml"),
+            format!("make_dir: true\nvars:\n  root: \"{}\"\nfiles:\n  - \"${{root}}/source\": \"${{root}}/target\"\nexcludes: []\n", root.display()),
+        ).unwrap();
+        let mut qbox = qb::qbox::Qbox::new("T", data_dir.to_path_buf()).unwrap();
+        qbox.open().unwrap();
+        qbox
+    };
+    let base = temp_boxes();
+    let home = base.path.join("home");
+    let qbox = make_box(&base.path, &home);
+    let git_dir = base.path.join("boxes/qbox_T/.git");
+    assert!(git_dir.exists());
+    qbox.new_version("v1").unwrap();
+    fs::write(home.join("source/a.txt"), "one\n").unwrap();
+    qbox.record("v1", true).unwrap();
+    fs::write(home.join("source/a.txt"), "two\n").unwrap();
+    qbox.record("v1", true).unwrap();
+    // Recording unchanged files leaves their content as it is, only the record time may change.
+    qbox.record("v1", true).unwrap();
+    assert_eq!(git(&git_dir, &["log", "--format=%s", "qbox/v1", "--", "files"]), "Record v1\nRecord v1\n");
+    assert_eq!(git(&git_dir, &["log", "--format=%s", "--reverse", "qbox/v1"]).lines().next(), Some("Create v1"));
+    assert_eq!(git(&git_dir, &["branch", "--list", "--format=%(refname:short)"]), "qbox/v1\n");
+    let invalid = qbox.new_version("v1.lock");
+    assert!(matches!(invalid, Err(qb::error::QboxError::InvalidRefName(_))), "got {:?}", invalid);
+    qbox.new_version("v2").unwrap();
+    assert!(!base.path.join("boxes/qbox_T/v1").exists(), "the version must live in git only");
+
+    let remote = base.path.join("remote.git");
+    let output = std::process::Command::new("git").args(["init", "-q", "--bare"]).arg(&remote).output().unwrap();
+    assert!(output.status.success());
+    git(&git_dir, &["push", "-q", "--all", &remote.to_string_lossy()]);
+
+    // Another machine fetches the versions and applies one without a version directory.
+    let other = temp_boxes();
+    let other_home = other.path.join("home");
+    let other_qbox = make_box(&other.path, &other_home);
+    git(&other.path.join("boxes/qbox_T/.git"), &["fetch", "-q", &remote.to_string_lossy(), "refs/heads/qbox/*:refs/heads/qbox/*"]);
+    assert_eq!(other_qbox.versions().unwrap(), ["v1", "v2"]);
+    // A fetched version that was never read can be deleted.
+    other_qbox.remove_version("v2", false).unwrap();
+    assert_eq!(other_qbox.versions().unwrap(), ["v1"]);
+    let result = other_qbox.apply("v1", false, false);
+    assert!(result.is_ok(), "expected Ok, but got {:?}", result);
+    assert_eq!(fs::read_to_string(other_home.join("target/a.txt")).unwrap(), "two\n");
+
+    other_qbox.remove_version("v1", true).unwrap();
+    assert!(other_qbox.versions().unwrap().is_empty());
+}
+
+#[test]
+fn qbox_memory_version_store_test(){
+    let (base, qbox) = temp_qbox_dirs();
+    let qbox = qbox.with_version_store(Box::new(qb::version_store::MemoryStore::new()));
+    let box_path = base.path.join("boxes/qbox_T");
+    qbox.new_version("v1").unwrap();
+    let again = qbox.new_version("v1");
+    assert!(matches!(again, Err(qb::error::QboxError::VersionExists(_))), "got {:?}", again);
+    qbox.record("v1", true).unwrap();
+    assert_eq!(qbox.versions().unwrap(), ["v1"]);
+    assert!(!box_path.join("v1").exists(), "the version must stay in memory");
+    assert!(qbox.verify("v1").unwrap().is_empty());
+    assert_eq!(qbox.version_infos().unwrap()[0].files, 2);
+
+    qbox.apply("v1", false, false).unwrap();
+    assert_eq!(fs::read_to_string(base.path.join("target/conf/b.txt")).unwrap(), "b\n");
+
+    let removed = qbox.remove_version("v1", false);
+    assert!(matches!(removed, Err(qb::error::QboxError::VersionNotEmpty(_))), "got {:?}", removed);
+    qbox.remove_version("v1", true).unwrap();
+    assert!(qbox.versions().unwrap().is_empty());
+    assert_eq!(qbox.collect_garbage().unwrap(), 0, "objects of the removed version must be deleted with it");
+}
+
+#[test]
+fn qbox_import_hostile_name_test(){
+    let base = temp_boxes();
+    let victim = base.path.join("victim");
+    fs::create_dir_all(&victim).unwrap();
+    fs::write(victim.join("keep.txt"), "keep\n").unwrap();
+
+    let archive = base.path.join("hostile.tar.zst");
+    let mut builder = tar::Builder::new(zstd::Encoder::new(fs::File::create(&archive).unwrap(), 0).unwrap());
+    let info = b"qbox: x/../../victim\nexported_at: 0\nversions: []\nfiles: []\n";
+    let mut header = tar::Header::new_gnu();
+    header.set_size(info.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, "box.yaml", info.as_slice()).unwrap();
+    builder.into_inner().unwrap().finish().unwrap();
+
+    let result = qb::archive::import(&archive, base.path.clone(), None, true);
+    assert!(matches!(result, Err(qb::error::QboxError::InvalidName(_))), "got {:?}", result);
+    assert_eq!(fs::read_to_string(victim.join("keep.txt")).unwrap(), "keep\n", "directory outside of the boxes was touched");
+    for name in ["../victim", ".hidden", "a/b", "nul\0"] {
+        let result = qb::archive::import(&archive, base.path.clone(), Some(name), true);
+        assert!(matches!(result, Err(qb::error::QboxError::InvalidName(_))), "{:?}: got {:?}", name, result);
+    }
+    let made = qb::qbox::make("../victim", base.path.clone());
+    assert!(matches!(made, Err(qb::error::QboxError::InvalidName(_))), "got {:?}", made);
+}
+
+#[test]
+fn qbox_import_hostile_paths_test(){
+    let base = temp_boxes();
+    let home = base.path.join("home");
+    fs::create_dir_all(home.join("source")).unwrap();
+    fs::create_dir_all(home.join("target/deep")).unwrap();
+    fs::write(home.join("source/a.txt"), "one\n").unwrap();
+    let box_path = base.path.join("boxes/qbox_T");
+    fs::create_dir_all(&box_path).unwrap();
+    fs::write(
+        box_path.join("qbox.yaml"),
+        format!("make_dir: true\nvars:\n  root: \"{}\"\nfiles:\n  - \"${{root}}/source\": \"${{root}}/target/deep\"\nexcludes: []\n", home.display()),
+    ).unwrap();
+    let mut qbox = qb::qbox::Qbox::new("T", base.path.clone()).unwrap();
+    qbox.open().unwrap();
+    qbox.new_version("v1").unwrap();
+    qbox.record("v1", true).unwrap();
+    let archive = base.path.join("v1.tar.zst");
+    qbox.export("v1", &archive).unwrap();
+
+    // Rewrites the manifest of the archive as version v2.
+    let rewrite = |name: &str, from: &str, to: &str| {
+        let changed = base.path.join(name);
+        let mut builder = tar::Builder::new(zstd::Encoder::new(fs::File::create(&changed).unwrap(), 0).unwrap());
+        let mut source = tar::Archive::new(zstd::Decoder::new(fs::File::open(&archive).unwrap()).unwrap());
+        for entry in source.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut header = entry.header().clone();
+            let mut content = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+            if entry.path().unwrap() == Path::new("version.yaml") {
+                assert!(content.contains(from), "{:?} not in {}", from, content);
+                content = content.replace("version: v1", "version: v2").replace(from, to);
+            }
+            header.set_size(content.len() as u64);
+            header.set_cksum();
+            builder.append(&header, content.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        changed
+    };
+    let escaped = home.join("escaped.txt");
+    let hostile = [
+        rewrite("parent.tar.zst", "relative: a.txt", "relative: ../../escaped.txt"),
+        rewrite("absolute.tar.zst", "relative: a.txt", &format!("relative: {}", escaped.display())),
+        rewrite("path.tar.zst", "path: files/0/a.txt", "path: ../files/0/a.txt"),
+        rewrite("mapping.tar.zst", "/target/deep", "/target"),
+    ];
+    for changed in hostile {
+        let result = qb::archive::import(&changed, base.path.clone(), None, false);
+        assert!(matches!(result, Err(qb::error::QboxError::InvalidArchive { .. })), "{}: got {:?}", changed.display(), result);
+        assert!(!qbox.versions().unwrap().contains(&"v2".to_string()), "{}: version was imported", changed.display());
+    }
+    qbox.apply("v1", false, false).unwrap();
+    assert!(!escaped.exists() && !home.join("target/a.txt").exists(), "file written outside of its mapping");
+    assert_eq!(fs::read_to_string(home.join("target/deep/a.txt")).unwrap(), "one\n");
+}